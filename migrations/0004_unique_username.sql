@@ -0,0 +1,12 @@
+-- Usernames used to be checked with a SELECT before the INSERT, so
+-- duplicates (also ones differing only in case) may exist. The oldest
+-- account keeps the name; the others get part of their id appended.
+UPDATE users u
+SET username = u.username || '_' || substr(u.id::text, 1, 8)
+WHERE EXISTS (
+    SELECT 1 FROM users o
+    WHERE lower(o.username) = lower(u.username)
+      AND (o.created_at, o.id) < (u.created_at, u.id)
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS users_username_lower_key ON users (lower(username));
@@ -0,0 +1,121 @@
+use axum::Json;
+use serde_json::{json, Value};
+use utoipa::{
+    openapi::security::{Http, HttpAuthScheme, SecurityScheme},
+    Modify, OpenApi,
+};
+
+use crate::{
+    blackjack::game::{HandView, Outcome, PlayerView, TableView, Turn},
+    card::{Card, CardFormat, CardSuit, CardValue},
+    chat::{ChatMessage, ChatScope},
+    db::user_data::{
+        LoginJson, LoginResponse, LookupJson, PatchUserJson, PostUserJson, PublicUser, User,
+    },
+    error::ErrorBody,
+    health::{Check, Health, Readiness},
+    websocket_manager::{MsgType, RecvWS, SendWS},
+};
+
+/// The REST API. Routes are listed here as well as on the router, so a new
+/// handler needs adding to both.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Blackjack backend"),
+    paths(
+        crate::get_card,
+        crate::add_user,
+        crate::login,
+        crate::add_guest,
+        crate::claim_guest,
+        crate::get_user_by_id,
+        crate::lookup_users,
+        crate::get_user,
+        crate::patch_user,
+        crate::delete_user,
+        crate::ws_handler,
+        crate::health::healthz,
+        crate::health::readyz,
+        crate::metrics::metrics_handler,
+    ),
+    components(schemas(
+        ErrorBody,
+        CardFormat,
+        User,
+        PublicUser,
+        PostUserJson,
+        LoginJson,
+        LoginResponse,
+        PatchUserJson,
+        LookupJson,
+        Health,
+        Check,
+        Readiness,
+    )),
+    modifiers(&BearerAuth),
+)]
+pub struct ApiDoc;
+
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        let mut scheme = Http::new(HttpAuthScheme::Bearer);
+        scheme.bearer_format = Some("JWT".to_string());
+        components.add_security_scheme("bearer", SecurityScheme::Http(scheme));
+    }
+}
+
+/// Schemas of the messages exchanged over `/ws`.
+#[derive(OpenApi)]
+#[openapi(components(schemas(
+    RecvWS,
+    SendWS,
+    MsgType,
+    ChatScope,
+    ChatMessage,
+    TableView,
+    PlayerView,
+    HandView,
+    Turn,
+    Outcome,
+    Card,
+    CardValue,
+    CardSuit,
+)))]
+struct WsDoc;
+
+/// OpenAPI document for the REST routes.
+pub async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+/// AsyncAPI document for the WebSocket protocol: clients publish `RecvWS`
+/// messages and receive `SendWS` ones, both as JSON text frames.
+pub async fn ws_schema() -> Json<Value> {
+    let schemas = WsDoc::openapi()
+        .components
+        .map(|x| x.schemas)
+        .unwrap_or_default();
+    Json(json!({
+        "asyncapi": "2.6.0",
+        "info": {
+            "title": "Blackjack game protocol",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "channels": {
+            "/ws": {
+                "publish": {
+                    "summary": "Commands sent by the client",
+                    "message": { "payload": { "$ref": "#/components/schemas/RecvWS" } },
+                },
+                "subscribe": {
+                    "summary": "Events sent by the server",
+                    "message": { "payload": { "$ref": "#/components/schemas/SendWS" } },
+                },
+            },
+        },
+        "components": { "schemas": schemas },
+    }))
+}
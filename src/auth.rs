@@ -0,0 +1,114 @@
+use std::sync::Arc;
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
+};
+use chrono::{Duration, Utc};
+use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{error::ApiError, AppState};
+
+// How long a session token issued at login stays valid, unless configured
+// otherwise.
+pub const TOKEN_TTL: Duration = Duration::hours(24);
+
+// Longest session token lifetime that may be configured.
+pub const MAX_TOKEN_TTL: Duration = Duration::days(365);
+
+// Shortest password accepted at registration.
+pub const MIN_PASSWORD_LEN: usize = 8;
+
+// Shortest session secret accepted, in bytes: anything shorter than the
+// HS256 output makes tokens easier to forge.
+pub const MIN_SECRET_LEN: usize = 32;
+
+/// Contents of a session token.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: Uuid,
+    pub name: String,
+    pub exp: i64,
+}
+
+/// Keys for signing and checking session tokens, derived from one secret.
+pub struct Keys {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    ttl: Duration,
+}
+
+impl Keys {
+    pub fn new(secret: &[u8], ttl: Duration) -> Self {
+        Keys {
+            encoding: EncodingKey::from_secret(secret),
+            decoding: DecodingKey::from_secret(secret),
+            ttl,
+        }
+    }
+
+    pub fn issue_token(
+        &self,
+        id: Uuid,
+        name: String,
+    ) -> Result<String, jsonwebtoken::errors::Error> {
+        let claims = Claims {
+            sub: id,
+            name,
+            exp: (Utc::now() + self.ttl).timestamp(),
+        };
+        jsonwebtoken::encode(&Header::default(), &claims, &self.encoding)
+    }
+
+    /// Checks the signature and expiry of a token and returns its claims.
+    pub fn verify_token(&self, token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
+        jsonwebtoken::decode::<Claims>(token, &self.decoding, &Validation::default())
+            .map(|data| data.claims)
+    }
+}
+
+pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+}
+
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(parsed) => Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Extracts the claims of a valid `Authorization: Bearer` session token.
+pub struct AuthUser(pub Claims);
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for AuthUser {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let TypedHeader(Authorization(bearer)) =
+            TypedHeader::<Authorization<Bearer>>::from_request_parts(parts, state)
+                .await
+                .map_err(|_| ApiError::Unauthorized("missing session token".to_string()))?;
+        state
+            .keys
+            .verify_token(bearer.token())
+            .map(AuthUser)
+            .map_err(|_| ApiError::Unauthorized("invalid session token".to_string()))
+    }
+}
@@ -1,38 +1,315 @@
+use std::{collections::HashMap, fmt};
+
+use serde::{Deserialize, Serialize};
 use strum::IntoEnumIterator;
+use tokio::sync::oneshot;
+use tracing::{debug, info, info_span, Span};
+use utoipa::ToSchema;
 use uuid::Uuid;
 
-use crate::card::{Card, CardSuit, CardValue};
+use crate::{
+    card::{Card, CardSuit, CardValue},
+    metrics,
+    websocket_manager::ConnKey,
+};
 
 use rand::{seq::SliceRandom, thread_rng};
 
 // Number of decks used by table.
 const NUM_OF_DECKS: u8 = 3;
 
+// Seats around a table, numbered from 1. Also the most a configured table
+// may have.
+pub const MAX_SEATS: u8 = 7;
+
+// Most spectators a single table will accept.
+const MAX_SPECTATORS: usize = 20;
+
+// The dealer keeps drawing until their hand reaches this total.
+const DEALER_STANDS_ON: u32 = 17;
+
+// Chips a new account starts with.
+pub const STARTING_CHIPS: u32 = 1000;
+
+// Smallest and largest bet allowed on a single spot.
+const MIN_BET: u32 = 1;
+const MAX_BET: u32 = 500;
+
+// Spots a player may bet on in one round.
+const MAX_SPOTS: usize = 3;
+
+// Hands a player may hold at once, counting hands made by splitting.
+const MAX_HANDS: usize = 6;
+
+// Most a configured table may allow on one spot, and spots per player.
+// Keeps every sum of bets and payouts well inside a u32.
+pub const MAX_BET_LIMIT: u32 = 1_000_000;
+pub const MAX_SPOTS_LIMIT: usize = MAX_HANDS;
+
+/// Rules every table is created with. The defaults are the constants above;
+/// the server configuration can override them.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TableRules {
+    pub max_seats: u8,
+    pub max_spectators: usize,
+    pub decks: u8,
+    pub dealer_stands_on: u32,
+    pub min_bet: u32,
+    pub max_bet: u32,
+    pub max_spots: usize,
+}
+
+impl Default for TableRules {
+    fn default() -> Self {
+        TableRules {
+            max_seats: MAX_SEATS,
+            max_spectators: MAX_SPECTATORS,
+            decks: NUM_OF_DECKS,
+            dealer_stands_on: DEALER_STANDS_ON,
+            min_bet: MIN_BET,
+            max_bet: MAX_BET,
+            max_spots: MAX_SPOTS,
+        }
+    }
+}
+
 struct PlayerCard {
     pub card: Card,
     pub visible: bool,
 }
 
+/// One spot in front of a player: its own bet, cards and settlement.
+struct Hand {
+    cards: Vec<PlayerCard>,
+    bet: u32,
+    stood: bool,
+    // Hands made by splitting cannot be a natural blackjack
+    split: bool,
+    outcome: Option<Outcome>,
+    payout: u32,
+}
+
 struct Player {
     id: Uuid,
-    hand: Vec<PlayerCard>,
+    seat: u8,
+    chips: u32,
+    hands: Vec<Hand>,
 }
 
 struct Table {
     id: Uuid,
+    host: Uuid,
+    rules: TableRules,
+    // Kept sorted by seat, which is also the order of play
     players: Vec<Player>,
+    spectators: Vec<Uuid>,
     deck: Vec<Card>,
     dealer: Vec<PlayerCard>,
+    in_round: bool,
+    // Hand being played while a round is in progress
+    turn: Option<Turn>,
+    // Balances settled by the last round, waiting to be saved
+    settled: Vec<Balance>,
+    // Rounds dealt so far, numbering each round's span
+    rounds: u32,
+    span: Span,
+    // Child of `span` for the round in progress
+    round_span: Span,
 }
 
 pub struct Blackjack {
+    rules: TableRules,
     tables: Vec<Table>,
+    // Table each player is seated at. Chips are loaded whole when a player
+    // sits down and saved whole afterwards, so a second seat would let them
+    // play the same chips twice.
+    seated: HashMap<Uuid, Uuid>,
+    // Balances of players who left, waiting to be saved
+    departed: Vec<Balance>,
+    // Set once the server starts shutting down: rounds under way may
+    // finish, but nobody can sit down or start a new one
+    closing: bool,
+}
+
+/// A player's chips after a round or on leaving a table, and how many hands
+/// they won, for saving to their account.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Balance {
+    pub id: Uuid,
+    pub chips: u32,
+    pub wins: u32,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, ToSchema)]
+pub enum Outcome {
+    Blackjack,
+    Win,
+    Lose,
+    Push,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    Bet { spots: Vec<u32> },
+    Deal,
+    Hit,
+    Stand,
+    Split,
+}
+
+/// Seat and hand index of the hand being played, ordered so that the next
+/// turn is always the next greater `Turn`.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord, ToSchema)]
+pub struct Turn {
+    pub seat: u8,
+    pub hand: usize,
+}
+
+/// A table as seen by the people at it: the dealer's hole card stays hidden
+/// until the round is over.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct TableView {
+    pub id: Uuid,
+    pub host: Uuid,
+    pub max_seats: u8,
+    pub players: Vec<PlayerView>,
+    pub spectators: Vec<Uuid>,
+    pub dealer: Vec<Option<Card>>,
+    pub in_round: bool,
+    pub turn: Option<Turn>,
+}
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct PlayerView {
+    pub id: Uuid,
+    pub seat: u8,
+    pub chips: u32,
+    pub hands: Vec<HandView>,
+}
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct HandView {
+    pub cards: Vec<Card>,
+    pub total: u32,
+    pub bet: u32,
+    pub stood: bool,
+    pub outcome: Option<Outcome>,
+    pub payout: u32,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum GameError {
+    TableNotFound,
+    AlreadySeated,
+    NotSeated,
+    TableFull,
+    SeatTaken,
+    InvalidSeat(u8),
+    AlreadyWatching,
+    SpectatorsFull(usize),
+    Spectating,
+    NotHost,
+    RoundInProgress,
+    NoRoundInProgress,
+    NotYourTurn,
+    InvalidBet { min: u32, max: u32 },
+    TooManySpots(usize),
+    InsufficientChips,
+    NoBets,
+    CannotSplit,
+    ShuttingDown,
+}
+
+impl fmt::Display for GameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GameError::TableNotFound => write!(f, "table was not found"),
+            GameError::AlreadySeated => write!(f, "already seated at a table"),
+            GameError::NotSeated => write!(f, "not seated at this table"),
+            GameError::TableFull => write!(f, "every seat at this table is taken"),
+            GameError::SeatTaken => write!(f, "that seat is taken"),
+            GameError::InvalidSeat(max) => write!(f, "seats are numbered 1 to {}", max),
+            GameError::AlreadyWatching => write!(f, "already watching this table"),
+            GameError::SpectatorsFull(max) => {
+                write!(f, "table already has {} spectators", max)
+            }
+            GameError::Spectating => write!(f, "spectators cannot act"),
+            GameError::NotHost => write!(f, "only the table host can do that"),
+            GameError::RoundInProgress => write!(f, "a round is already in progress"),
+            GameError::NoRoundInProgress => write!(f, "no round is in progress"),
+            GameError::NotYourTurn => write!(f, "it is not your turn"),
+            GameError::InvalidBet { min, max } => {
+                write!(f, "bets must be between {} and {}", min, max)
+            }
+            GameError::TooManySpots(max) => write!(f, "at most {} spots can be played", max),
+            GameError::InsufficientChips => write!(f, "not enough chips"),
+            GameError::NoBets => write!(f, "no bets have been placed"),
+            GameError::CannotSplit => write!(f, "this hand cannot be split"),
+            GameError::ShuttingDown => write!(f, "the server is shutting down"),
+        }
+    }
+}
+
+impl std::error::Error for GameError {}
+
+/// What happened to a table when somebody left it.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Departure {
+    Left,
+    HostChanged(Uuid),
+    TableClosed,
+}
+
+#[derive(Debug)]
+pub enum Command {
+    CreateTable {
+        key: ConnKey,
+        seat: Option<u8>,
+        chips: u32,
+        resp: oneshot::Sender<Result<Uuid, GameError>>,
+    },
+    JoinTable {
+        table_id: Uuid,
+        key: ConnKey,
+        seat: Option<u8>,
+        chips: u32,
+        resp: oneshot::Sender<Result<(), GameError>>,
+    },
+    WatchTable {
+        table_id: Uuid,
+        key: ConnKey,
+        resp: oneshot::Sender<Result<(), GameError>>,
+    },
+    LeaveTable {
+        table_id: Uuid,
+        key: ConnKey,
+        resp: oneshot::Sender<Result<(), GameError>>,
+    },
+    Act {
+        table_id: Uuid,
+        key: ConnKey,
+        action: Action,
+        resp: oneshot::Sender<Result<(), GameError>>,
+    },
+    /// Answers straight away, showing the game task is alive.
+    Ping { resp: oneshot::Sender<()> },
+    /// Stops new players and rounds, answering once no round is in progress.
+    Drain { resp: oneshot::Sender<()> },
+    /// Closes every table, refunding bets that were not settled.
+    CloseAll { resp: oneshot::Sender<()> },
 }
 
 impl Blackjack {
-    pub fn create_game() -> Blackjack {
+    pub fn create_game(rules: TableRules) -> Blackjack {
         let tables: Vec<Table> = Vec::new();
-        Blackjack { tables }
+        Blackjack {
+            rules,
+            tables,
+            seated: HashMap::new(),
+            departed: Vec::new(),
+            closing: false,
+        }
     }
     pub fn get_table(&self, id: Uuid) -> Option<usize> {
         self.tables.iter().position(|x: &Table| x.id == id)
@@ -47,22 +324,281 @@ impl Blackjack {
             None => false,
         }
     }
-    pub fn add_table(&mut self) -> Uuid {
-        self.tables.push(Table {
-            id: Uuid::new_v4(),
+    pub fn add_table(
+        &mut self,
+        host: Uuid,
+        seat: Option<u8>,
+        chips: u32,
+    ) -> Result<Uuid, GameError> {
+        if self.closing {
+            return Err(GameError::ShuttingDown);
+        }
+        if self.seated.contains_key(&host) {
+            return Err(GameError::AlreadySeated);
+        }
+        let id = Uuid::new_v4();
+        let mut table = Table {
+            id,
+            host,
+            rules: self.rules,
             players: Vec::new(),
-            deck: Blackjack::create_deck(),
+            spectators: Vec::new(),
+            deck: Blackjack::create_deck(self.rules.decks),
             dealer: Vec::new(),
+            in_round: false,
+            turn: None,
+            settled: Vec::new(),
+            rounds: 0,
+            span: info_span!("table", table_id = %id),
+            round_span: Span::none(),
+        };
+        let seat = table.choose_seat(seat)?;
+        table.add_player(host, seat, chips);
+        table
+            .span
+            .in_scope(|| info!(host = %host, seat, "table created"));
+        self.tables.push(table);
+        self.seated.insert(host, id);
+        Ok(id)
+    }
+
+    /// Seats a player, in the requested seat or else the lowest free one,
+    /// and returns the seat number.
+    pub fn join_table(
+        &mut self,
+        table_id: Uuid,
+        id: Uuid,
+        seat: Option<u8>,
+        chips: u32,
+    ) -> Result<u8, GameError> {
+        if self.closing {
+            return Err(GameError::ShuttingDown);
+        }
+        let index = self.get_table(table_id).ok_or(GameError::TableNotFound)?;
+        if self.seated.contains_key(&id) {
+            return Err(GameError::AlreadySeated);
+        }
+        let table = &mut self.tables[index];
+        let seat = table.choose_seat(seat)?;
+        // Taking a seat ends watching
+        table.spectators.retain(|x| *x != id);
+        table.add_player(id, seat, chips);
+        self.seated.insert(id, table_id);
+        table
+            .span()
+            .in_scope(|| info!(player_id = %id, seat, chips, "player joined"));
+        Ok(seat)
+    }
+
+    pub fn watch_table(&mut self, table_id: Uuid, id: Uuid) -> Result<(), GameError> {
+        if self.closing {
+            return Err(GameError::ShuttingDown);
+        }
+        let index = self.get_table(table_id).ok_or(GameError::TableNotFound)?;
+        let table = &mut self.tables[index];
+        if table.get_player(id).is_some() {
+            return Err(GameError::AlreadySeated);
+        }
+        if table.spectators.contains(&id) {
+            return Err(GameError::AlreadyWatching);
+        }
+        if table.spectators.len() >= table.rules.max_spectators {
+            return Err(GameError::SpectatorsFull(table.rules.max_spectators));
+        }
+        table.spectators.push(id);
+        table
+            .span()
+            .in_scope(|| debug!(spectator_id = %id, "spectator joined"));
+        Ok(())
+    }
+
+    /// Removes a player or spectator from a table, handing the table to the
+    /// next player if the host leaves and closing it once no players are
+    /// left.
+    pub fn leave_table(&mut self, table_id: Uuid, id: Uuid) -> Result<Departure, GameError> {
+        let index = self.get_table(table_id).ok_or(GameError::TableNotFound)?;
+        let table = &mut self.tables[index];
+        let span = table.span();
+        let _enter = span.enter();
+        if let Some(num) = table.spectators.iter().position(|x| *x == id) {
+            table.spectators.remove(num);
+            debug!(spectator_id = %id, "spectator left");
+            return Ok(Departure::Left);
+        }
+        let num = table.get_player(id).ok_or(GameError::NotSeated)?;
+        let player = &table.players[num];
+        // Bets not yet dealt go back to the player; bets in play are lost
+        let pending: u32 = player
+            .hands
+            .iter()
+            .filter(|x| x.is_pending())
+            .map(|x| x.bet)
+            .sum();
+        self.departed.push(Balance {
+            id,
+            chips: player.chips + pending,
+            wins: 0,
         });
-        self.tables.last().unwrap().id
+        let seat = player.seat;
+        table.remove_player(id);
+        self.seated.remove(&id);
+        info!(player_id = %id, seat, refunded = pending, "player left");
+        // The round moves on if it was waiting on the leaver
+        if table.turn.is_some_and(|x| x.seat == seat) {
+            table.advance_turn();
+        }
+
+        match table.players.first() {
+            None => {
+                self.remove_table(table_id);
+                info!("table closed");
+                Ok(Departure::TableClosed)
+            }
+            Some(next) if table.host == id => {
+                table.host = next.id;
+                Ok(Departure::HostChanged(next.id))
+            }
+            Some(_) => Ok(Departure::Left),
+        }
     }
 
-    pub fn create_deck() -> Vec<Card> {
+    pub fn act(&mut self, table_id: Uuid, id: Uuid, action: Action) -> Result<(), GameError> {
+        let index = self.get_table(table_id).ok_or(GameError::TableNotFound)?;
+        let table = &mut self.tables[index];
+        if table.spectators.contains(&id) {
+            return Err(GameError::Spectating);
+        }
+        let num = table.get_player(id).ok_or(GameError::NotSeated)?;
+        if self.closing && matches!(action, Action::Bet { .. } | Action::Deal) {
+            return Err(GameError::ShuttingDown);
+        }
+        let span = table.span();
+        let _enter = span.enter();
+        debug!(player_id = %id, ?action, "player acted");
+
+        match action {
+            Action::Bet { spots } => {
+                if table.in_round {
+                    return Err(GameError::RoundInProgress);
+                }
+                let rules = table.rules;
+                table.players[num].place_bets(&spots, &rules)?;
+            }
+            Action::Deal => {
+                if table.host != id {
+                    return Err(GameError::NotHost);
+                }
+                if table.in_round {
+                    return Err(GameError::RoundInProgress);
+                }
+                if !table.players.iter().any(|x| x.has_pending_bets()) {
+                    return Err(GameError::NoBets);
+                }
+                table.deal();
+            }
+            Action::Hit | Action::Stand | Action::Split => {
+                if !table.in_round {
+                    return Err(GameError::NoRoundInProgress);
+                }
+                let seat = table.players[num].seat;
+                let turn = match table.turn {
+                    Some(turn) if turn.seat == seat => turn,
+                    _ => return Err(GameError::NotYourTurn),
+                };
+                match action {
+                    Action::Hit => {
+                        let card = table.draw();
+                        table.players[num].hands[turn.hand].cards.push(PlayerCard {
+                            card,
+                            visible: true,
+                        });
+                    }
+                    Action::Split => table.split(num, turn.hand)?,
+                    _ => table.players[num].hands[turn.hand].stood = true,
+                }
+
+                let hand = &mut table.players[num].hands[turn.hand];
+                if hand_total(&hand.cards) >= 21 {
+                    hand.stood = true;
+                }
+                if hand.stood {
+                    table.advance_turn();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Refuses new players, bets and rounds from now on.
+    pub fn stop_accepting(&mut self) {
+        self.closing = true;
+    }
+
+    pub fn table_count(&self) -> usize {
+        self.tables.len()
+    }
+
+    pub fn player_count(&self) -> usize {
+        self.tables.iter().map(|x| x.players.len()).sum()
+    }
+
+    pub fn round_in_progress(&self) -> bool {
+        self.tables.iter().any(|x| x.in_round)
+    }
+
+    /// Closes every table and returns their ids. Bets that were not settled,
+    /// dealt or not, go back to the players; the balances are left for
+    /// `take_balances`.
+    pub fn close_all(&mut self) -> Vec<Uuid> {
+        let mut closed = Vec::new();
+        for mut table in self.tables.drain(..) {
+            let _enter = table.span().entered();
+            info!(
+                players = table.players.len(),
+                in_round = table.in_round,
+                "table closed for shutdown"
+            );
+            self.departed.append(&mut table.settled);
+            for player in table.players.iter() {
+                let refund: u32 = player
+                    .hands
+                    .iter()
+                    .filter(|x| x.outcome.is_none())
+                    .map(|x| x.bet)
+                    .sum();
+                self.departed.push(Balance {
+                    id: player.id,
+                    chips: player.chips + refund,
+                    wins: 0,
+                });
+            }
+            closed.push(table.id);
+        }
+        self.seated.clear();
+        closed
+    }
+
+    /// Drains the balances that changed since the last call, so the caller
+    /// can save them.
+    pub fn take_balances(&mut self) -> Vec<Balance> {
+        let mut balances: Vec<Balance> = self.departed.drain(..).collect();
+        for table in self.tables.iter_mut() {
+            balances.append(&mut table.settled);
+        }
+        balances
+    }
+
+    pub fn view(&self, table_id: Uuid) -> Option<TableView> {
+        let index = self.get_table(table_id)?;
+        Some(self.tables[index].view())
+    }
+
+    pub fn create_deck(decks: u8) -> Vec<Card> {
         let mut deck: Vec<Card> = Vec::new();
         for suit in CardSuit::iter() {
             for value in CardValue::iter() {
                 let card = Card { suit, value };
-                for _num in 1..=NUM_OF_DECKS {
+                for _num in 1..=decks {
                     deck.push(card);
                 }
             }
@@ -73,13 +609,86 @@ impl Blackjack {
     }
 }
 
+impl Player {
+    fn has_pending_bets(&self) -> bool {
+        self.hands.iter().any(|x| x.is_pending())
+    }
+
+    /// Replaces any bets not yet dealt with one hand per spot, taking the
+    /// chips for them.
+    fn place_bets(&mut self, spots: &[u32], rules: &TableRules) -> Result<(), GameError> {
+        if spots.len() > rules.max_spots {
+            return Err(GameError::TooManySpots(rules.max_spots));
+        }
+        if spots
+            .iter()
+            .any(|x| !(rules.min_bet..=rules.max_bet).contains(x))
+        {
+            return Err(GameError::InvalidBet {
+                min: rules.min_bet,
+                max: rules.max_bet,
+            });
+        }
+        let pending: u32 = self
+            .hands
+            .iter()
+            .filter(|x| x.is_pending())
+            .map(|x| x.bet)
+            .sum();
+        let available = self.chips + pending;
+        let total: u32 = spots.iter().sum();
+        if total > available {
+            return Err(GameError::InsufficientChips);
+        }
+
+        self.chips = available - total;
+        self.hands = spots.iter().map(|bet| Hand::new(*bet)).collect();
+        Ok(())
+    }
+}
+
+impl Hand {
+    fn new(bet: u32) -> Self {
+        Hand {
+            cards: Vec::new(),
+            bet,
+            stood: false,
+            split: false,
+            outcome: None,
+            payout: 0,
+        }
+    }
+
+    /// A bet placed for the next round that has not been dealt yet.
+    fn is_pending(&self) -> bool {
+        self.cards.is_empty() && self.outcome.is_none()
+    }
+
+    fn is_natural(&self) -> bool {
+        !self.split && self.cards.len() == 2 && hand_total(&self.cards) == 21
+    }
+}
+
 impl Table {
-    pub fn add_player(&mut self, id: Uuid) {
+    /// The span to log under: the round's while one is in progress, the
+    /// table's otherwise.
+    fn span(&self) -> Span {
+        if self.in_round {
+            self.round_span.clone()
+        } else {
+            self.span.clone()
+        }
+    }
+
+    pub fn add_player(&mut self, id: Uuid, seat: u8, chips: u32) {
         let player = Player {
             id,
-            hand: Vec::new(),
+            seat,
+            chips,
+            hands: Vec::new(),
         };
-        self.players.push(player);
+        let index = self.players.partition_point(|x| x.seat < seat);
+        self.players.insert(index, player);
     }
 
     pub fn get_player(&self, id: Uuid) -> Option<usize> {
@@ -97,91 +706,511 @@ impl Table {
         }
     }
 
-    pub fn add_card(&mut self, id: Uuid) -> bool {
-        let index = self.get_player(id);
-        match index {
-            Some(num) => {
-                self.players[num].hand.push(PlayerCard {
-                    card: self.deck.pop().unwrap(),
-                    visible: true,
-                });
-                true
+    fn choose_seat(&self, seat: Option<u8>) -> Result<u8, GameError> {
+        let taken = |seat: u8| self.players.iter().any(|x| x.seat == seat);
+        match seat {
+            Some(seat) if seat == 0 || seat > self.rules.max_seats => {
+                Err(GameError::InvalidSeat(self.rules.max_seats))
             }
-            None => false,
+            Some(seat) if taken(seat) => Err(GameError::SeatTaken),
+            Some(seat) => Ok(seat),
+            None => (1..=self.rules.max_seats)
+                .find(|seat| !taken(*seat))
+                .ok_or(GameError::TableFull),
         }
     }
 
-    pub fn add_card_dealer(&mut self) {
-        self.dealer.push(PlayerCard {
-            card: self.deck.pop().unwrap(),
-            visible: false,
-        });
+    pub fn add_card_dealer(&mut self, visible: bool) {
+        let card = self.draw();
+        self.dealer.push(PlayerCard { card, visible });
     }
 
-    pub fn check_winner(&self, id: Uuid) -> bool {
-        let index = self.get_player(id);
-        match index {
-            Some(num) => {
-                // ( total with ace = 1, ( if ace present , total with one ace = 11) )
-                let mut player_total = (0, (false, 0));
-                for player_card in self.players[num].hand.iter() {
-                    let PlayerCard {
-                        card,
-                        visible: _visible,
-                    } = player_card;
-                    player_total.0 += card.value as i32 + 1;
-                    match card.value as i32 {
-                        0 => {
-                            if !player_total.1 .0 {
-                                player_total.1 .1 += card.value as i32 + 11;
-                                player_total.1 .0 = true;
-                            } else {
-                                player_total.1 .1 += card.value as i32 + 1;
-                            }
-                        }
-                        _ => player_total.1 .1 += card.value as i32 + 1,
-                    }
-                }
-                // ( total with ace = 1, ( if ace present , total with one ace = 11) )
-                let mut dealer_total = (0, (false, 0));
-                for player_card in self.dealer.as_slice() {
-                    let PlayerCard {
+    /// Takes the next card, starting a fresh shoe if this one has run out.
+    fn draw(&mut self) -> Card {
+        match self.deck.pop() {
+            Some(card) => card,
+            None => {
+                self.deck = Blackjack::create_deck(self.rules.decks);
+                self.deck.pop().expect("a new deck is never empty")
+            }
+        }
+    }
+
+    /// Starts a round: two cards to every hand with a bet on it, and an up
+    /// card and a hole card to the dealer. Players without a bet sit out.
+    fn deal(&mut self) {
+        self.rounds += 1;
+        self.round_span = info_span!(parent: &self.span, "round", round = self.rounds);
+        let _enter = self.round_span.clone().entered();
+        self.dealer.clear();
+        for player in self.players.iter_mut() {
+            player.hands.retain(|x| x.is_pending());
+        }
+
+        for _ in 0..2 {
+            for num in 0..self.players.len() {
+                for hand in 0..self.players[num].hands.len() {
+                    let card = self.draw();
+                    self.players[num].hands[hand].cards.push(PlayerCard {
                         card,
-                        visible: _visible,
-                    } = player_card;
-                    dealer_total.0 += card.value as i32 + 1;
-                    match card.value as i32 {
-                        0 => {
-                            if !dealer_total.1 .0 {
-                                dealer_total.1 .1 += card.value as i32 + 11;
-                                dealer_total.1 .0 = true;
-                            } else {
-                                dealer_total.1 .1 += card.value as i32 + 1;
-                            }
-                        }
-                        _ => dealer_total.1 .1 += card.value as i32 + 1,
-                    }
-                }
-                if (dealer_total.0 > player_total.0 || dealer_total.0 > player_total.1 .1)
-                    || (dealer_total.1 .1 > player_total.0 || dealer_total.1 .1 > player_total.1 .1)
-                        && dealer_total.0 <= 21
-                {
-                    true
-                } else {
-                    false
+                        visible: true,
+                    });
                 }
             }
-            None => false,
         }
+        self.add_card_dealer(true);
+        self.add_card_dealer(false);
+        self.in_round = true;
+        let hands: usize = self.players.iter().map(|x| x.hands.len()).sum();
+        info!(hands, "round started");
+
+        for hand in self.players.iter_mut().flat_map(|x| x.hands.iter_mut()) {
+            hand.stood = hand_total(&hand.cards) >= 21;
+        }
+        self.turn = None;
+        self.advance_turn();
+    }
+
+    /// Splits a pair into two hands, the new one carrying an equal bet and
+    /// played straight after the original.
+    fn split(&mut self, num: usize, hand: usize) -> Result<(), GameError> {
+        let player = &mut self.players[num];
+        let cards = &player.hands[hand].cards;
+        let pair = cards.len() == 2 && hand_total(&cards[..1]) == hand_total(&cards[1..]);
+        if !pair || player.hands.len() >= MAX_HANDS {
+            return Err(GameError::CannotSplit);
+        }
+        let bet = player.hands[hand].bet;
+        if player.chips < bet {
+            return Err(GameError::InsufficientChips);
+        }
+
+        player.chips -= bet;
+        let mut new_hand = Hand::new(bet);
+        new_hand.split = true;
+        new_hand.cards.extend(player.hands[hand].cards.pop());
+        player.hands[hand].split = true;
+        player.hands.insert(hand + 1, new_hand);
+
+        for index in [hand, hand + 1] {
+            let card = self.draw();
+            let new_card = PlayerCard {
+                card,
+                visible: true,
+            };
+            self.players[num].hands[index].cards.push(new_card);
+        }
+        let second = &mut self.players[num].hands[hand + 1];
+        second.stood = hand_total(&second.cards) >= 21;
+        Ok(())
     }
 
-    pub fn check_winners_player(&self) -> Vec<Uuid> {
-        let mut winners: Vec<Uuid> = Vec::new();
-        for player in self.players.iter() {
-            if self.check_winner(player.id) {
-                winners.push(player.id);
+    /// Passes the turn to the next unfinished hand by seat, finishing the
+    /// round once there is nothing left to play.
+    fn advance_turn(&mut self) {
+        let current = self.turn;
+        self.turn = self
+            .players
+            .iter()
+            .flat_map(|x| {
+                x.hands.iter().enumerate().map(|(hand, h)| {
+                    let turn = Turn { seat: x.seat, hand };
+                    (turn, h.stood)
+                })
+            })
+            .find(|(turn, stood)| Some(*turn) > current && !stood)
+            .map(|(turn, _)| turn);
+        if self.turn.is_none() {
+            self.finish_round();
+        }
+    }
+
+    /// The dealer reveals, draws to the `dealer_stands_on` total and each
+    /// hand is settled and paid.
+    fn finish_round(&mut self) {
+        let _enter = self.round_span.clone().entered();
+        for card in self.dealer.iter_mut() {
+            card.visible = true;
+        }
+        while hand_total(&self.dealer) < self.rules.dealer_stands_on {
+            self.add_card_dealer(true);
+        }
+
+        let dealer_total = hand_total(&self.dealer);
+        let dealer_natural = self.dealer.len() == 2 && dealer_total == 21;
+        for player in self.players.iter_mut() {
+            if player.hands.is_empty() {
+                continue;
+            }
+            let mut wins = 0;
+            for hand in player.hands.iter_mut() {
+                let result = outcome(hand, dealer_total, dealer_natural);
+                hand.payout = match result {
+                    Outcome::Blackjack => hand.bet + hand.bet * 3 / 2,
+                    Outcome::Win => hand.bet * 2,
+                    Outcome::Push => hand.bet,
+                    Outcome::Lose => 0,
+                };
+                if hand.payout > hand.bet {
+                    wins += 1;
+                }
+                hand.outcome = Some(result);
+                player.chips += hand.payout;
+                metrics::get().hand_settled(result, hand.bet, hand.payout);
+                debug!(
+                    player_id = %player.id,
+                    bet = hand.bet,
+                    payout = hand.payout,
+                    outcome = ?result,
+                    "hand settled"
+                );
             }
+            self.settled.push(Balance {
+                id: player.id,
+                chips: player.chips,
+                wins,
+            });
+        }
+        self.in_round = false;
+        metrics::get().rounds_completed.inc();
+        info!(dealer_total, "round finished");
+    }
+
+    fn view(&self) -> TableView {
+        TableView {
+            id: self.id,
+            host: self.host,
+            max_seats: self.rules.max_seats,
+            players: self
+                .players
+                .iter()
+                .map(|x| PlayerView {
+                    id: x.id,
+                    seat: x.seat,
+                    chips: x.chips,
+                    hands: x
+                        .hands
+                        .iter()
+                        .map(|h| HandView {
+                            cards: h.cards.iter().map(|c| c.card).collect(),
+                            total: hand_total(&h.cards),
+                            bet: h.bet,
+                            stood: h.stood,
+                            outcome: h.outcome,
+                            payout: h.payout,
+                        })
+                        .collect(),
+                })
+                .collect(),
+            spectators: self.spectators.clone(),
+            dealer: self
+                .dealer
+                .iter()
+                .map(|c| if c.visible { Some(c.card) } else { None })
+                .collect(),
+            in_round: self.in_round,
+            turn: self.turn,
+        }
+    }
+}
+
+/// Best blackjack total for a hand: aces count as 11 unless that busts.
+fn hand_total(hand: &[PlayerCard]) -> u32 {
+    let mut total = 0;
+    let mut has_ace = false;
+    for player_card in hand {
+        // CardValue is ordered Ace, Two, .., King
+        let points = (player_card.card.value as u32 + 1).min(10);
+        total += points;
+        has_ace |= points == 1;
+    }
+    if has_ace && total + 10 <= 21 {
+        total + 10
+    } else {
+        total
+    }
+}
+
+fn outcome(hand: &Hand, dealer_total: u32, dealer_natural: bool) -> Outcome {
+    let player_total = hand_total(&hand.cards);
+    if player_total > 21 {
+        Outcome::Lose
+    } else if hand.is_natural() {
+        if dealer_natural {
+            Outcome::Push
+        } else {
+            Outcome::Blackjack
         }
-        winners
+    } else if dealer_natural {
+        Outcome::Lose
+    } else if dealer_total > 21 || player_total > dealer_total {
+        Outcome::Win
+    } else if player_total == dealer_total {
+        Outcome::Push
+    } else {
+        Outcome::Lose
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use CardValue::*;
+
+    fn cards(values: &[CardValue]) -> Vec<PlayerCard> {
+        values
+            .iter()
+            .map(|value| PlayerCard {
+                card: Card {
+                    value: *value,
+                    suit: CardSuit::Spades,
+                },
+                visible: true,
+            })
+            .collect()
+    }
+
+    fn hand(values: &[CardValue], split: bool) -> Hand {
+        let mut hand = Hand::new(10);
+        hand.cards = cards(values);
+        hand.split = split;
+        hand
+    }
+
+    /// Stacks the shoe of the only table so cards come out in `order`.
+    fn rig(game: &mut Blackjack, order: &[CardValue]) {
+        game.tables[0].deck = order
+            .iter()
+            .rev()
+            .map(|value| Card {
+                value: *value,
+                suit: CardSuit::Spades,
+            })
+            .collect();
+    }
+
+    fn turn(game: &Blackjack) -> Option<Turn> {
+        game.tables[0].turn
+    }
+
+    /// A table with players at the given seats, the first hosting, each
+    /// with 1000 chips and a bet of 10 on one spot.
+    fn table(seats: &[u8]) -> (Blackjack, Uuid, Vec<Uuid>) {
+        let mut game = Blackjack::create_game(TableRules::default());
+        let ids: Vec<Uuid> = seats.iter().map(|_| Uuid::new_v4()).collect();
+        let table_id = game.add_table(ids[0], Some(seats[0]), 1000).unwrap();
+        for (id, seat) in ids.iter().zip(seats).skip(1) {
+            game.join_table(table_id, *id, Some(*seat), 1000).unwrap();
+        }
+        for id in ids.iter() {
+            let bet = Action::Bet { spots: vec![10] };
+            game.act(table_id, *id, bet).unwrap();
+        }
+        (game, table_id, ids)
+    }
+
+    #[test]
+    fn totals_count_aces_soft_until_they_would_bust() {
+        assert_eq!(hand_total(&cards(&[Ace, Six])), 17);
+        assert_eq!(hand_total(&cards(&[Ace, Six, Ten])), 17);
+        assert_eq!(hand_total(&cards(&[Ace, Ace, Nine])), 21);
+        assert_eq!(hand_total(&cards(&[Ace, King])), 21);
+        assert_eq!(hand_total(&cards(&[King, Queen, Two])), 22);
+        assert_eq!(hand_total(&cards(&[Jack, Queen])), 20);
+    }
+
+    #[test]
+    fn naturals_against_the_dealer() {
+        let natural = hand(&[Ace, King], false);
+        assert_eq!(outcome(&natural, 21, true), Outcome::Push);
+        assert_eq!(outcome(&natural, 21, false), Outcome::Blackjack);
+        assert_eq!(outcome(&natural, 20, false), Outcome::Blackjack);
+
+        // Three cards to 21 or 21 after a split is not a natural
+        let three = hand(&[Seven, Seven, Seven], false);
+        assert_eq!(outcome(&three, 21, true), Outcome::Lose);
+        assert_eq!(outcome(&three, 21, false), Outcome::Push);
+        let split = hand(&[Ace, King], true);
+        assert_eq!(outcome(&split, 21, true), Outcome::Lose);
+        assert_eq!(outcome(&split, 20, false), Outcome::Win);
+
+        let bust = hand(&[King, Queen, Two], false);
+        assert_eq!(outcome(&bust, 25, false), Outcome::Lose);
+        assert_eq!(
+            outcome(&hand(&[Ten, Eight], false), 25, false),
+            Outcome::Win
+        );
+    }
+
+    #[test]
+    fn bets_replace_pending_bets_and_are_checked() {
+        let rules = TableRules::default();
+        let mut player = Player {
+            id: Uuid::new_v4(),
+            seat: 1,
+            chips: 100,
+            hands: Vec::new(),
+        };
+
+        player.place_bets(&[30, 20], &rules).unwrap();
+        assert_eq!(player.chips, 50);
+        assert_eq!(player.hands.len(), 2);
+        // A new bet gives back the chips of the old one first
+        player.place_bets(&[100], &rules).unwrap();
+        assert_eq!(player.chips, 0);
+        assert_eq!(player.hands.len(), 1);
+
+        assert_eq!(
+            player.place_bets(&[60, 60], &rules),
+            Err(GameError::InsufficientChips)
+        );
+        assert_eq!(
+            player.place_bets(&[0], &rules),
+            Err(GameError::InvalidBet {
+                min: rules.min_bet,
+                max: rules.max_bet
+            })
+        );
+        assert_eq!(
+            player.place_bets(&[1; MAX_SPOTS + 1], &rules),
+            Err(GameError::TooManySpots(MAX_SPOTS))
+        );
+        // Failed bets leave the earlier one in place
+        assert_eq!(player.chips, 0);
+        assert_eq!(player.hands[0].bet, 100);
+    }
+
+    #[test]
+    fn split_hands_are_played_in_seat_order() {
+        // The host sits at seat 2 but seat 1 plays first
+        let (mut game, table_id, ids) = table(&[2, 1]);
+        let (host, first) = (ids[0], ids[1]);
+        rig(
+            &mut game,
+            &[Eight, Ten, Eight, Nine, Ten, Seven, Three, Two],
+        );
+        game.act(table_id, host, Action::Deal).unwrap();
+        assert_eq!(turn(&game), Some(Turn { seat: 1, hand: 0 }));
+
+        game.act(table_id, first, Action::Split).unwrap();
+        let player = &game.tables[0].players[0];
+        assert_eq!(player.chips, 980);
+        assert_eq!(hand_total(&player.hands[0].cards), 11);
+        assert_eq!(hand_total(&player.hands[1].cards), 10);
+        assert_eq!(turn(&game), Some(Turn { seat: 1, hand: 0 }));
+
+        assert_eq!(
+            game.act(table_id, host, Action::Stand),
+            Err(GameError::NotYourTurn)
+        );
+        game.act(table_id, first, Action::Stand).unwrap();
+        assert_eq!(turn(&game), Some(Turn { seat: 1, hand: 1 }));
+        game.act(table_id, first, Action::Stand).unwrap();
+        assert_eq!(turn(&game), Some(Turn { seat: 2, hand: 0 }));
+        game.act(table_id, host, Action::Stand).unwrap();
+        assert_eq!(turn(&game), None);
+        assert!(!game.tables[0].in_round);
+
+        // Dealer stands on 17: both split hands lose, 19 wins
+        let balances = game.take_balances();
+        let chips = |id| balances.iter().find(|x| x.id == id).unwrap().chips;
+        assert_eq!(chips(first), 980);
+        assert_eq!(chips(host), 1010);
+    }
+
+    #[test]
+    fn natural_pays_three_to_two() {
+        let (mut game, table_id, ids) = table(&[1]);
+        rig(&mut game, &[Ace, King, Ten, Seven]);
+        game.act(table_id, ids[0], Action::Deal).unwrap();
+
+        // A natural needs no turn, so the round is over straight away
+        assert!(!game.tables[0].in_round);
+        let hand = &game.tables[0].players[0].hands[0];
+        assert_eq!(hand.outcome, Some(Outcome::Blackjack));
+        assert_eq!(hand.payout, 25);
+        let balance = game.take_balances()[0];
+        assert_eq!(balance.chips, 1015);
+        assert_eq!(balance.wins, 1);
+    }
+
+    #[test]
+    fn leaving_on_your_turn_moves_the_round_on() {
+        let (mut game, table_id, ids) = table(&[1, 3]);
+        let (leaver, other) = (ids[0], ids[1]);
+        rig(&mut game, &[Ten, Ten, Six, Seven, Ten, Eight]);
+        game.act(table_id, leaver, Action::Deal).unwrap();
+        assert_eq!(turn(&game), Some(Turn { seat: 1, hand: 0 }));
+
+        let departure = game.leave_table(table_id, leaver).unwrap();
+        assert_eq!(departure, Departure::HostChanged(other));
+        assert_eq!(turn(&game), Some(Turn { seat: 3, hand: 0 }));
+
+        game.act(table_id, other, Action::Stand).unwrap();
+        assert!(!game.tables[0].in_round);
+
+        // The bet in play is lost on leaving
+        let balances = game.take_balances();
+        let chips = |id| balances.iter().find(|x| x.id == id).unwrap().chips;
+        assert_eq!(chips(leaver), 990);
+        assert_eq!(chips(other), 990);
+    }
+
+    #[test]
+    fn leaving_refunds_bets_not_yet_dealt() {
+        let (mut game, table_id, ids) = table(&[1, 2]);
+        let bet = Action::Bet {
+            spots: vec![25, 15],
+        };
+        game.act(table_id, ids[1], bet).unwrap();
+
+        assert_eq!(game.leave_table(table_id, ids[1]), Ok(Departure::Left));
+        let balance = game.take_balances()[0];
+        assert_eq!((balance.id, balance.chips, balance.wins), (ids[1], 1000, 0));
+
+        assert_eq!(
+            game.leave_table(table_id, ids[0]),
+            Ok(Departure::TableClosed)
+        );
+        assert_eq!(game.take_balances()[0].chips, 1000);
+        assert_eq!(game.table_count(), 0);
+    }
+
+    #[test]
+    fn players_sit_at_one_table_at_a_time() {
+        let (mut game, table_id, ids) = table(&[1]);
+        let player = ids[0];
+        let other = game.add_table(Uuid::new_v4(), None, 1000).unwrap();
+
+        assert_eq!(
+            game.add_table(player, None, 1000),
+            Err(GameError::AlreadySeated)
+        );
+        assert_eq!(
+            game.join_table(other, player, None, 1000),
+            Err(GameError::AlreadySeated)
+        );
+        assert_eq!(
+            game.join_table(table_id, player, None, 1000),
+            Err(GameError::AlreadySeated)
+        );
+
+        game.leave_table(table_id, player).unwrap();
+        assert_eq!(game.join_table(other, player, None, 1000), Ok(2));
+    }
+
+    #[test]
+    fn closing_refunds_unsettled_bets() {
+        let (mut game, table_id, ids) = table(&[1, 2]);
+        rig(&mut game, &[Ten, Ten, Six, Seven, Ten, Eight]);
+        game.act(table_id, ids[0], Action::Deal).unwrap();
+        assert!(game.round_in_progress());
+
+        assert_eq!(game.close_all(), vec![table_id]);
+        let balances = game.take_balances();
+        assert_eq!(balances.len(), 2);
+        assert!(balances.iter().all(|x| x.chips == 1000 && x.wins == 0));
+        assert_eq!(game.table_count(), 0);
     }
 }
@@ -1,7 +1,47 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    error::Error,
+    fmt,
+    str::FromStr,
+    sync::{Arc, LazyLock, Mutex},
+};
+
+use axum::body::Bytes;
+use resvg::{tiny_skia, usvg};
 use serde::{Deserialize, Serialize};
-use strum_macros::{Display, EnumIter};
+use strum_macros::EnumIter;
+use utoipa::{IntoParams, ToSchema};
+
+// Width of a PNG when the request does not give one, about the size the
+// SVGs are drawn at.
+pub const DEFAULT_PNG_WIDTH: u32 = 240;
+
+// Bounds on the width of a PNG, in pixels.
+pub const MIN_PNG_WIDTH: u32 = 16;
+pub const MAX_PNG_WIDTH: u32 = 1024;
+
+// Alternate art is numbered from 2; 1 is the usual image.
+pub const MAX_VARIANT: u8 = 2;
 
-#[derive(Clone, Copy, Debug, Deserialize, Display, EnumIter, Serialize)]
+// Rendered PNGs kept in memory. The oldest is dropped to make room.
+const PNG_CACHE_SIZE: usize = 256;
+
+// System fonts, loaded on the first render. The cards ask for Bitstream
+// Vera Sans and Arial; DejaVu Sans is derived from Vera, so it stands in
+// for whichever of them is not installed.
+static FONTS: LazyLock<Arc<usvg::fontdb::Database>> = LazyLock::new(|| {
+    let mut fonts = usvg::fontdb::Database::new();
+    fonts.load_system_fonts();
+    fonts.set_serif_family("DejaVu Sans");
+    Arc::new(fonts)
+});
+
+/// Card values are written as their names, `Ace` to `King`. Parsing, which
+/// both the HTTP routes and the game protocol go through, ignores case and
+/// also takes the numbers `2` to `10` and the letters `a`, `t`, `j`, `q`
+/// and `k`.
+#[derive(Clone, Copy, Debug, Deserialize, EnumIter, Serialize, PartialEq, Eq, ToSchema)]
+#[serde(try_from = "String")]
 pub enum CardValue {
     Ace,
     Two,
@@ -17,7 +57,11 @@ pub enum CardValue {
     Queen,
     King,
 }
-#[derive(Clone, Copy, Debug, Deserialize, Display, EnumIter, Serialize)]
+
+/// Suits are written as their names, `Clubs` to `Spades`. Parsing ignores
+/// case and also takes the singular and the initial letter.
+#[derive(Clone, Copy, Debug, Deserialize, EnumIter, Serialize, PartialEq, Eq, ToSchema)]
+#[serde(try_from = "String")]
 pub enum CardSuit {
     Clubs,
     Diamonds,
@@ -25,41 +69,417 @@ pub enum CardSuit {
     Spades,
 }
 
-#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, ToSchema)]
 pub struct Card {
     pub value: CardValue,
     pub suit: CardSuit,
 }
 
-pub fn get_card_file(value: String, suit: String) -> Result<String, &'static str> {
-    match value.to_lowercase().as_str() {
-        "ace" => CardValue::Ace,
-        "2" => CardValue::Two,
-        "3" => CardValue::Three,
-        "4" => CardValue::Four,
-        "5" => CardValue::Five,
-        "6" => CardValue::Six,
-        "7" => CardValue::Seven,
-        "8" => CardValue::Eight,
-        "9" => CardValue::Nine,
-        "10" => CardValue::Ten,
-        "jack" => CardValue::Jack,
-        "queen" => CardValue::Queen,
-        "king" => CardValue::King,
-        _ => return Err("invalid card value"),
-    };
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JokerColor {
+    Red,
+    Black,
+}
+
+/// One of the images in `assets/cards`: a card, or a joker, which is never
+/// dealt but has art all the same.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CardImage {
+    Card(Card),
+    Joker(JokerColor),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CardError {
+    Value(String),
+    Suit(String),
+    Joker(String),
+}
+
+impl fmt::Display for CardError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CardError::Value(value) => write!(f, "invalid card value {:?}", value),
+            CardError::Suit(suit) => write!(f, "invalid card suit {:?}", suit),
+            CardError::Joker(color) => {
+                write!(f, "invalid joker {:?}, expected red or black", color)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CardError {}
+
+impl CardValue {
+    fn name(self) -> &'static str {
+        match self {
+            CardValue::Ace => "Ace",
+            CardValue::Two => "Two",
+            CardValue::Three => "Three",
+            CardValue::Four => "Four",
+            CardValue::Five => "Five",
+            CardValue::Six => "Six",
+            CardValue::Seven => "Seven",
+            CardValue::Eight => "Eight",
+            CardValue::Nine => "Nine",
+            CardValue::Ten => "Ten",
+            CardValue::Jack => "Jack",
+            CardValue::Queen => "Queen",
+            CardValue::King => "King",
+        }
+    }
+
+    // How the value is written in the names of the image files.
+    fn file_name(self) -> &'static str {
+        match self {
+            CardValue::Ace => "ace",
+            CardValue::Two => "2",
+            CardValue::Three => "3",
+            CardValue::Four => "4",
+            CardValue::Five => "5",
+            CardValue::Six => "6",
+            CardValue::Seven => "7",
+            CardValue::Eight => "8",
+            CardValue::Nine => "9",
+            CardValue::Ten => "10",
+            CardValue::Jack => "jack",
+            CardValue::Queen => "queen",
+            CardValue::King => "king",
+        }
+    }
+}
+
+impl fmt::Display for CardValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for CardValue {
+    type Err = CardError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let value = match s.to_ascii_lowercase().as_str() {
+            "ace" | "a" => CardValue::Ace,
+            "two" | "2" => CardValue::Two,
+            "three" | "3" => CardValue::Three,
+            "four" | "4" => CardValue::Four,
+            "five" | "5" => CardValue::Five,
+            "six" | "6" => CardValue::Six,
+            "seven" | "7" => CardValue::Seven,
+            "eight" | "8" => CardValue::Eight,
+            "nine" | "9" => CardValue::Nine,
+            "ten" | "10" | "t" => CardValue::Ten,
+            "jack" | "j" => CardValue::Jack,
+            "queen" | "q" => CardValue::Queen,
+            "king" | "k" => CardValue::King,
+            _ => return Err(CardError::Value(s.to_string())),
+        };
+        Ok(value)
+    }
+}
+
+impl TryFrom<String> for CardValue {
+    type Error = CardError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl CardSuit {
+    fn name(self) -> &'static str {
+        match self {
+            CardSuit::Clubs => "Clubs",
+            CardSuit::Diamonds => "Diamonds",
+            CardSuit::Hearts => "Hearts",
+            CardSuit::Spades => "Spades",
+        }
+    }
+}
+
+impl fmt::Display for CardSuit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for CardSuit {
+    type Err = CardError;
 
-    let card_suit: CardSuit = match suit.to_lowercase().as_str() {
-        "clubs" => CardSuit::Clubs,
-        "diamonds" => CardSuit::Diamonds,
-        "hearts" => CardSuit::Hearts,
-        "spades" => CardSuit::Spades,
-        _ => return Err("invalid card suit"),
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let suit = match s.to_ascii_lowercase().as_str() {
+            "clubs" | "club" | "c" => CardSuit::Clubs,
+            "diamonds" | "diamond" | "d" => CardSuit::Diamonds,
+            "hearts" | "heart" | "h" => CardSuit::Hearts,
+            "spades" | "spade" | "s" => CardSuit::Spades,
+            _ => return Err(CardError::Suit(s.to_string())),
+        };
+        Ok(suit)
+    }
+}
+
+impl TryFrom<String> for CardSuit {
+    type Error = CardError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl CardImage {
+    /// Reads the value and suit of a card route. A joker is asked for with
+    /// the value `joker` and the suit `red` or `black`.
+    pub fn parse(value: &str, suit: &str) -> Result<Self, CardError> {
+        if value.eq_ignore_ascii_case("joker") {
+            let color = match suit.to_ascii_lowercase().as_str() {
+                "red" => JokerColor::Red,
+                "black" => JokerColor::Black,
+                _ => return Err(CardError::Joker(suit.to_string())),
+            };
+            return Ok(CardImage::Joker(color));
+        }
+        Ok(CardImage::Card(Card {
+            value: value.parse()?,
+            suit: suit.parse()?,
+        }))
+    }
+
+    /// Name of the image file without its extension, e.g. `10_of_hearts`.
+    /// Variants above 1 are alternate art, which only some cards have.
+    pub fn file_name(self, variant: u8) -> String {
+        let name = match self {
+            CardImage::Card(card) => format!(
+                "{}_of_{}",
+                card.value.file_name(),
+                card.suit.name().to_lowercase()
+            ),
+            CardImage::Joker(JokerColor::Red) => "red_joker".to_string(),
+            CardImage::Joker(JokerColor::Black) => "black_joker".to_string(),
+        };
+        if variant > 1 {
+            format!("{}{}", name, variant)
+        } else {
+            name
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum CardFormat {
+    #[default]
+    Svg,
+    Png,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct CardQuery {
+    /// `svg` unless given.
+    pub format: Option<CardFormat>,
+    /// Width of a PNG in pixels; the height keeps the card's proportions.
+    pub width: Option<u32>,
+    /// `2` for the alternate art some face cards have.
+    pub variant: Option<u8>,
+}
+
+/// Rasterizes a card SVG to a PNG `width` pixels wide.
+pub fn render_png(svg: &[u8], width: u32) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let options = usvg::Options {
+        fontdb: FONTS.clone(),
+        ..usvg::Options::default()
     };
+    let tree = usvg::Tree::from_data(svg, &options)?;
+
+    let size = tree.size();
+    let scale = width as f32 / size.width();
+    let height = (size.height() * scale).round() as u32;
+    let mut pixmap = tiny_skia::Pixmap::new(width, height).ok_or("invalid image size")?;
+    resvg::render(
+        &tree,
+        tiny_skia::Transform::from_scale(scale, scale),
+        &mut pixmap.as_mut(),
+    );
+    Ok(pixmap.encode_png()?)
+}
+
+/// PNGs already rendered, by card file and width.
+pub struct PngCache {
+    inner: Mutex<PngCacheInner>,
+}
+
+struct PngCacheInner {
+    images: HashMap<(String, u32), Bytes>,
+    // Keys in the order they were added, oldest first
+    order: VecDeque<(String, u32)>,
+}
+
+impl PngCache {
+    pub fn new() -> Self {
+        PngCache {
+            inner: Mutex::new(PngCacheInner {
+                images: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub fn get(&self, file_name: &str, width: u32) -> Option<Bytes> {
+        let inner = self.inner.lock().unwrap();
+        inner.images.get(&(file_name.to_string(), width)).cloned()
+    }
+
+    pub fn insert(&self, file_name: String, width: u32, png: Bytes) {
+        let mut inner = self.inner.lock().unwrap();
+        let key = (file_name, width);
+        if inner.images.insert(key.clone(), png).is_some() {
+            return;
+        }
+        inner.order.push_back(key);
+        if inner.order.len() > PNG_CACHE_SIZE {
+            if let Some(oldest) = inner.order.pop_front() {
+                inner.images.remove(&oldest);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use strum::IntoEnumIterator;
+
+    use super::*;
+
+    fn exists(image: CardImage, variant: u8) -> bool {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join(format!("assets/cards/{}.svg", image.file_name(variant)))
+            .is_file()
+    }
+
+    #[test]
+    fn values_parse_from_names_numbers_and_letters() {
+        let aliases: [(CardValue, &[&str]); 13] = [
+            (CardValue::Ace, &["ace", "a"]),
+            (CardValue::Two, &["two", "2"]),
+            (CardValue::Three, &["three", "3"]),
+            (CardValue::Four, &["four", "4"]),
+            (CardValue::Five, &["five", "5"]),
+            (CardValue::Six, &["six", "6"]),
+            (CardValue::Seven, &["seven", "7"]),
+            (CardValue::Eight, &["eight", "8"]),
+            (CardValue::Nine, &["nine", "9"]),
+            (CardValue::Ten, &["ten", "10", "t"]),
+            (CardValue::Jack, &["jack", "j"]),
+            (CardValue::Queen, &["queen", "q"]),
+            (CardValue::King, &["king", "k"]),
+        ];
+        for (value, names) in aliases {
+            for name in names {
+                assert_eq!(name.parse::<CardValue>(), Ok(value));
+                assert_eq!(name.to_uppercase().parse::<CardValue>(), Ok(value));
+            }
+            assert_eq!(value.to_string().parse::<CardValue>(), Ok(value));
+        }
+        for bad in ["1", "11", "", "joker", "aces"] {
+            assert_eq!(
+                bad.parse::<CardValue>(),
+                Err(CardError::Value(bad.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn suits_parse_from_names_singulars_and_initials() {
+        let aliases = [
+            (CardSuit::Clubs, ["clubs", "club", "c"]),
+            (CardSuit::Diamonds, ["diamonds", "diamond", "d"]),
+            (CardSuit::Hearts, ["hearts", "heart", "h"]),
+            (CardSuit::Spades, ["spades", "spade", "s"]),
+        ];
+        for (suit, names) in aliases {
+            for name in names {
+                assert_eq!(name.parse::<CardSuit>(), Ok(suit));
+                assert_eq!(name.to_uppercase().parse::<CardSuit>(), Ok(suit));
+            }
+            assert_eq!(suit.to_string().parse::<CardSuit>(), Ok(suit));
+        }
+        assert_eq!(
+            "x".parse::<CardSuit>(),
+            Err(CardError::Suit("x".to_string()))
+        );
+    }
+
+    #[test]
+    fn the_protocol_parses_cards_the_same_way() {
+        let card: Card = serde_json::from_str(r#"{"value": "q", "suit": "HEART"}"#).unwrap();
+        assert_eq!(
+            card,
+            Card {
+                value: CardValue::Queen,
+                suit: CardSuit::Hearts
+            }
+        );
+        // Cards still go out under their full names
+        assert_eq!(
+            serde_json::to_string(&card).unwrap(),
+            r#"{"value":"Queen","suit":"Hearts"}"#
+        );
+        assert!(serde_json::from_str::<CardValue>(r#""eleven""#).is_err());
+    }
+
+    #[test]
+    fn every_card_has_an_image() {
+        for suit in CardSuit::iter() {
+            for value in CardValue::iter() {
+                let image = CardImage::Card(Card { value, suit });
+                assert!(exists(image, 1), "{} of {}", value, suit);
+            }
+        }
+        for color in ["red", "black"] {
+            let image = CardImage::parse("Joker", color).unwrap();
+            assert!(exists(image, 1), "{} joker", color);
+        }
+    }
+
+    #[test]
+    fn file_names_follow_the_assets() {
+        let name = |value: &str, suit: &str, variant| {
+            CardImage::parse(value, suit).unwrap().file_name(variant)
+        };
+        assert_eq!(name("t", "h", 1), "10_of_hearts");
+        assert_eq!(name("A", "s", 1), "ace_of_spades");
+        assert_eq!(name("k", "c", 2), "king_of_clubs2");
+        assert_eq!(name("joker", "RED", 1), "red_joker");
+        assert_eq!(name("joker", "black", 1), "black_joker");
+        assert_eq!(
+            CardImage::parse("joker", "blue"),
+            Err(CardError::Joker("blue".to_string()))
+        );
+        assert_eq!(
+            CardImage::parse("jack", "x"),
+            Err(CardError::Suit("x".to_string()))
+        );
+    }
 
-    Ok(format!(
-        "{}_of_{}",
-        value.to_string().to_lowercase(),
-        card_suit.to_string().to_lowercase(),
-    ))
+    #[test]
+    fn alternate_art_exists_for_faces_and_the_ace_of_spades() {
+        for suit in CardSuit::iter() {
+            for value in CardValue::iter() {
+                let image = CardImage::Card(Card { value, suit });
+                let alternate =
+                    matches!(value, CardValue::Jack | CardValue::Queen | CardValue::King)
+                        || (value, suit) == (CardValue::Ace, CardSuit::Spades);
+                assert_eq!(
+                    exists(image, MAX_VARIANT),
+                    alternate,
+                    "{} of {}",
+                    value,
+                    suit
+                );
+            }
+        }
+    }
 }
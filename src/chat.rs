@@ -0,0 +1,209 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{config::ChatConfig, rate_limit::TokenBucket};
+
+// Longest chat message accepted, in characters, unless configured
+// otherwise.
+pub const MAX_CHAT_LEN: usize = 280;
+
+// Burst of chat messages a user may send before being rate limited, unless
+// configured otherwise.
+pub const CHAT_BURST: u32 = 5;
+
+// Chat messages regained per second once the burst is spent, unless
+// configured otherwise.
+pub const CHAT_REFILL_PER_SEC: f64 = 0.5;
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, ToSchema)]
+pub enum ChatScope {
+    Lobby,
+    Table,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct ChatMessage {
+    pub scope: ChatScope,
+    pub table_id: Option<Uuid>,
+    /// Sender's `[name, id]`.
+    #[schema(value_type = Vec<String>, min_items = 2, max_items = 2)]
+    pub from: (String, Uuid),
+    pub text: String,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ChatError {
+    Empty,
+    TooLong(usize),
+    RateLimited,
+    NotAtTable,
+    Muted,
+    NotHost,
+}
+
+impl fmt::Display for ChatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChatError::Empty => write!(f, "message is empty"),
+            ChatError::TooLong(max) => {
+                write!(f, "message is longer than {} characters", max)
+            }
+            ChatError::RateLimited => write!(f, "sending messages too quickly"),
+            ChatError::NotAtTable => write!(f, "not at a table"),
+            ChatError::Muted => write!(f, "you are muted at this table"),
+            ChatError::NotHost => write!(f, "only the table host can do that"),
+        }
+    }
+}
+
+impl std::error::Error for ChatError {}
+
+/// Masks configured words in chat messages. Matching is case-insensitive
+/// and on whole words, so blocking "ass" leaves "class" alone.
+#[derive(Debug, Default)]
+pub struct ChatFilter {
+    blocked: Vec<String>,
+}
+
+impl ChatFilter {
+    pub fn new<I, S>(words: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let blocked = words
+            .into_iter()
+            .map(|w| w.as_ref().trim().to_lowercase())
+            .filter(|w| !w.is_empty())
+            .collect();
+        ChatFilter { blocked }
+    }
+
+    pub fn apply(&self, text: &str) -> String {
+        if self.blocked.is_empty() {
+            return text.to_string();
+        }
+
+        let mut out = String::with_capacity(text.len());
+        let mut word = String::new();
+        for c in text.chars() {
+            if c.is_alphanumeric() {
+                word.push(c);
+            } else {
+                self.push_word(&mut out, &word);
+                word.clear();
+                out.push(c);
+            }
+        }
+        self.push_word(&mut out, &word);
+        out
+    }
+
+    fn push_word(&self, out: &mut String, word: &str) {
+        if self.blocked.contains(&word.to_lowercase()) {
+            out.extend(word.chars().map(|_| '*'));
+        } else {
+            out.push_str(word);
+        }
+    }
+}
+
+/// Per-connection chat state: enforces the length limit and rate limit and
+/// runs the filter before a message reaches the `WebSocketManager`.
+pub struct ChatLimiter {
+    bucket: TokenBucket,
+    max_len: usize,
+}
+
+impl ChatLimiter {
+    pub fn new(config: &ChatConfig) -> Self {
+        ChatLimiter {
+            bucket: TokenBucket::new(config.burst, config.per_sec),
+            max_len: config.max_len,
+        }
+    }
+
+    pub fn check(&mut self, filter: &ChatFilter, text: &str) -> Result<String, ChatError> {
+        let text = text.trim();
+        if text.is_empty() {
+            return Err(ChatError::Empty);
+        }
+        if text.chars().count() > self.max_len {
+            return Err(ChatError::TooLong(self.max_len));
+        }
+        if !self.bucket.try_take() {
+            return Err(ChatError::RateLimited);
+        }
+        Ok(filter.apply(text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(max_len: usize, burst: u32) -> ChatLimiter {
+        // Refills too slowly to matter while a test runs
+        ChatLimiter::new(&ChatConfig {
+            blocked_words: Vec::new(),
+            max_len,
+            burst,
+            per_sec: 1e-9,
+        })
+    }
+
+    #[test]
+    fn blocked_words_are_masked_whole_and_in_any_case() {
+        let filter = ChatFilter::new([" Ass ", "", "darn"]);
+        assert_eq!(filter.apply("what an ass"), "what an ***");
+        assert_eq!(filter.apply("ASS!"), "***!");
+        assert_eq!(filter.apply("Darn it, darn."), "**** it, ****.");
+        // Only whole words
+        assert_eq!(
+            filter.apply("first class, assorted"),
+            "first class, assorted"
+        );
+        assert_eq!(filter.apply("darned"), "darned");
+        // One star per character, not per byte
+        let filter = ChatFilter::new(["ÉCLAIR"]);
+        assert_eq!(filter.apply("an éclair"), "an ******");
+    }
+
+    #[test]
+    fn no_blocked_words_leaves_text_alone() {
+        let filter = ChatFilter::default();
+        assert_eq!(filter.apply("anything  goes "), "anything  goes ");
+        // Blank entries block nothing
+        let filter = ChatFilter::new(["", "  "]);
+        assert_eq!(filter.apply("a b"), "a b");
+    }
+
+    #[test]
+    fn messages_are_trimmed_checked_and_filtered() {
+        let filter = ChatFilter::new(["darn"]);
+        let mut limiter = limiter(5, 10);
+        assert_eq!(limiter.check(&filter, "  hi  "), Ok("hi".to_string()));
+        assert_eq!(limiter.check(&filter, "darn"), Ok("****".to_string()));
+        assert_eq!(limiter.check(&filter, "   "), Err(ChatError::Empty));
+        // Length is counted in characters, after trimming
+        assert_eq!(limiter.check(&filter, " ééééé "), Ok("ééééé".to_string()));
+        assert_eq!(
+            limiter.check(&filter, "toolong"),
+            Err(ChatError::TooLong(5))
+        );
+    }
+
+    #[test]
+    fn messages_over_the_rate_are_refused() {
+        let filter = ChatFilter::default();
+        let mut limiter = limiter(MAX_CHAT_LEN, 2);
+        assert!(limiter.check(&filter, "one").is_ok());
+        // Refused messages do not use up the burst
+        assert_eq!(limiter.check(&filter, ""), Err(ChatError::Empty));
+        assert!(limiter.check(&filter, "two").is_ok());
+        assert_eq!(limiter.check(&filter, "three"), Err(ChatError::RateLimited));
+    }
+}
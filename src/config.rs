@@ -0,0 +1,474 @@
+use std::{
+    env, fmt, fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use http::HeaderValue;
+use serde::Deserialize;
+
+use crate::{
+    auth::{MAX_TOKEN_TTL, MIN_SECRET_LEN},
+    blackjack::game::{TableRules, MAX_BET_LIMIT, MAX_SEATS, MAX_SPOTS_LIMIT},
+};
+
+// File read when `CONFIG_FILE` is not set. It may be absent, in which case
+// the defaults and environment apply.
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+/// Everything the server can be configured with. Values come from the
+/// defaults below, then the TOML file, then the environment variables named
+/// on each field.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub cors: CorsConfig,
+    pub rate_limit: RateLimitConfig,
+    pub chat: ChatConfig,
+    pub table: TableRules,
+    pub timers: TimerConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// `BIND_ADDR`
+    pub bind: SocketAddr,
+    /// `ASSET_DIR`
+    pub asset_dir: PathBuf,
+    /// Capacity of the channels into the manager and game tasks.
+    pub channel_size: usize,
+    /// Used when `RUST_LOG` is not set.
+    pub log_filter: String,
+    /// `LOG_FORMAT`, `text` or `json`.
+    pub log_format: LogFormat,
+    /// `TLS_CERT` and `TLS_KEY`, PEM files. With both set the server
+    /// speaks HTTPS and WSS only.
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// `SESSION_SECRET`, the key session tokens are signed with. Required.
+    pub session_secret: String,
+}
+
+/// How log lines are written to stdout.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines.
+    #[default]
+    Text,
+    /// One JSON object per line, for log collectors.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {:?}, expected text or json", s)),
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: SocketAddr::from(([0, 0, 0, 0], 3000)),
+            asset_dir: PathBuf::from("assets"),
+            channel_size: 10,
+            log_filter: "blackjack_backend=debug,tower_http=debug".to_string(),
+            log_format: LogFormat::Text,
+            tls_cert: None,
+            tls_key: None,
+            session_secret: String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// `DATABASE_URL`. Without one, users are kept in memory.
+    pub url: Option<String>,
+    /// `DB_POOL_SIZE`
+    pub pool_size: u32,
+    /// `DB_TLS`. Requires TLS to the database when set and never uses it
+    /// otherwise, whatever `sslmode` the URL gives.
+    pub tls: bool,
+    /// `DB_CA_FILE`, a PEM bundle of the CAs trusted to sign the database
+    /// certificate. Without one the usual public CAs are trusted.
+    pub ca_file: Option<PathBuf>,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            url: None,
+            pool_size: 10,
+            tls: false,
+            ca_file: None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// `CORS_ORIGINS`, comma separated. Empty allows any origin. Also
+    /// checked against the `Origin` of WebSocket upgrades.
+    pub origins: Vec<String>,
+    /// `CORS_ALLOW_CREDENTIALS`. Lets browsers send cookies and
+    /// `Authorization` along with cross-origin requests, which needs an
+    /// explicit list of origins.
+    pub allow_credentials: bool,
+}
+
+impl CorsConfig {
+    /// Whether a browser page served from `origin` may use the API.
+    pub fn allows(&self, origin: &str) -> bool {
+        self.origins.is_empty() || self.origins.iter().any(|x| x.eq_ignore_ascii_case(origin))
+    }
+}
+
+/// Token bucket sizes: each bucket holds a burst of requests and refills at
+/// a steady rate.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// REST requests per client IP.
+    pub http_burst: u32,
+    pub http_per_sec: f64,
+    /// Accounts, guest or registered, created per client IP.
+    pub account_burst: u32,
+    pub account_per_sec: f64,
+    /// Inbound messages per WebSocket connection.
+    pub ws_burst: u32,
+    pub ws_per_sec: f64,
+    /// Messages a connection may have rejected in quick succession before
+    /// it is disconnected. One is forgiven every second.
+    pub ws_max_rejected: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            http_burst: 30,
+            http_per_sec: 10.0,
+            account_burst: 5,
+            account_per_sec: 0.1,
+            ws_burst: 20,
+            ws_per_sec: 10.0,
+            ws_max_rejected: 20,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChatConfig {
+    /// `CHAT_BLOCKED_WORDS`, comma separated. Masked in every message.
+    pub blocked_words: Vec<String>,
+    /// Longest message accepted, in characters.
+    pub max_len: usize,
+    /// Messages per WebSocket connection, as a token bucket.
+    pub burst: u32,
+    pub per_sec: f64,
+}
+
+impl Default for ChatConfig {
+    fn default() -> Self {
+        ChatConfig {
+            blocked_words: Vec::new(),
+            max_len: crate::chat::MAX_CHAT_LEN,
+            burst: crate::chat::CHAT_BURST,
+            per_sec: crate::chat::CHAT_REFILL_PER_SEC,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimerConfig {
+    /// How long a socket write may take before the connection is dropped.
+    pub write_timeout_secs: u64,
+    /// How long a session token stays valid.
+    pub session_ttl_secs: u64,
+    /// How long rounds under way get to finish on shutdown before their
+    /// bets are refunded.
+    pub shutdown_grace_secs: u64,
+}
+
+impl Default for TimerConfig {
+    fn default() -> Self {
+        TimerConfig {
+            write_timeout_secs: crate::websocket_manager::WRITE_TIMEOUT.as_secs(),
+            session_ttl_secs: crate::auth::TOKEN_TTL.num_seconds() as u64,
+            shutdown_grace_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Env(&'static str, String),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "could not read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "could not parse {}: {}", path.display(), e),
+            ConfigError::Env(var, e) => write!(f, "invalid {}: {}", var, e),
+            ConfigError::Invalid(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Loads the file named by `CONFIG_FILE` (or `config.toml` if present),
+    /// applies environment overrides and validates the result.
+    pub fn load() -> Result<Config, ConfigError> {
+        let config = Config::read()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Like `load`, but only checks the database settings, which are all
+    /// running the migrations needs.
+    pub fn load_for_migrations() -> Result<Config, ConfigError> {
+        let config = Config::read()?;
+        config.validate_database()?;
+        Ok(config)
+    }
+
+    fn read() -> Result<Config, ConfigError> {
+        let mut config = match env::var("CONFIG_FILE") {
+            Ok(path) => Config::from_file(Path::new(&path))?,
+            Err(_) if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Config::from_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            Err(_) => Config::default(),
+        };
+        config.apply_env()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|e| ConfigError::Read(path.into(), e))?;
+        toml::from_str(&text).map_err(|e| ConfigError::Parse(path.into(), e))
+    }
+
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        if let Some(bind) = parse_env("BIND_ADDR")? {
+            self.server.bind = bind;
+        }
+        if let Some(format) = parse_env("LOG_FORMAT")? {
+            self.server.log_format = format;
+        }
+        if let Ok(dir) = env::var("ASSET_DIR") {
+            self.server.asset_dir = PathBuf::from(dir);
+        }
+        if let Ok(path) = env::var("TLS_CERT") {
+            self.server.tls_cert = Some(PathBuf::from(path));
+        }
+        if let Ok(path) = env::var("TLS_KEY") {
+            self.server.tls_key = Some(PathBuf::from(path));
+        }
+        if let Ok(secret) = env::var("SESSION_SECRET") {
+            self.server.session_secret = secret;
+        }
+        if let Ok(url) = env::var("DATABASE_URL") {
+            self.database.url = Some(url);
+        }
+        if let Some(size) = parse_env("DB_POOL_SIZE")? {
+            self.database.pool_size = size;
+        }
+        if let Some(tls) = parse_env("DB_TLS")? {
+            self.database.tls = tls;
+        }
+        if let Ok(path) = env::var("DB_CA_FILE") {
+            self.database.ca_file = Some(PathBuf::from(path));
+        }
+        if let Ok(origins) = env::var("CORS_ORIGINS") {
+            self.cors.origins = origins
+                .split(',')
+                .map(|x| x.trim().to_string())
+                .filter(|x| !x.is_empty())
+                .collect();
+        }
+        if let Some(allow) = parse_env("CORS_ALLOW_CREDENTIALS")? {
+            self.cors.allow_credentials = allow;
+        }
+        if let Ok(words) = env::var("CHAT_BLOCKED_WORDS") {
+            self.chat.blocked_words = words.split(',').map(|x| x.trim().to_string()).collect();
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |msg: &str| Err(ConfigError::Invalid(msg.to_string()));
+
+        if self.server.channel_size == 0 {
+            return invalid("server.channel_size must be at least 1");
+        }
+        if !self.server.asset_dir.is_dir() {
+            return Err(ConfigError::Invalid(format!(
+                "server.asset_dir {} is not a directory",
+                self.server.asset_dir.display()
+            )));
+        }
+        match (&self.server.tls_cert, &self.server.tls_key) {
+            (Some(cert), Some(key)) => {
+                for (name, path) in [("tls_cert", cert), ("tls_key", key)] {
+                    if !path.is_file() {
+                        return Err(ConfigError::Invalid(format!(
+                            "server.{} {} is not a file",
+                            name,
+                            path.display()
+                        )));
+                    }
+                }
+            }
+            (None, None) => {}
+            _ => return invalid("server.tls_cert and server.tls_key must be set together"),
+        }
+        if self.server.session_secret.len() < MIN_SECRET_LEN {
+            return Err(ConfigError::Invalid(format!(
+                "server.session_secret (SESSION_SECRET) must be at least {} bytes",
+                MIN_SECRET_LEN
+            )));
+        }
+        self.validate_database()?;
+        for origin in &self.cors.origins {
+            // An origin is a scheme and host with an optional port, nothing
+            // after it, since that is all a browser sends
+            let host = origin
+                .strip_prefix("http://")
+                .or_else(|| origin.strip_prefix("https://"));
+            let valid = host.is_some_and(|x| !x.is_empty() && !x.contains('/'))
+                && HeaderValue::from_str(origin).is_ok();
+            if !valid {
+                return Err(ConfigError::Invalid(format!(
+                    "cors.origins entry {:?} must be an http(s) origin without a path",
+                    origin
+                )));
+            }
+        }
+        if self.cors.allow_credentials && self.cors.origins.is_empty() {
+            return invalid(
+                "cors.allow_credentials needs cors.origins to list the allowed origins",
+            );
+        }
+
+        let limits = &self.rate_limit;
+        for (name, burst, per_sec) in [
+            ("http", limits.http_burst, limits.http_per_sec),
+            ("account", limits.account_burst, limits.account_per_sec),
+            ("ws", limits.ws_burst, limits.ws_per_sec),
+        ] {
+            if burst == 0 || !(per_sec > 0.0 && per_sec.is_finite()) {
+                return Err(ConfigError::Invalid(format!(
+                    "rate_limit.{0}_burst must be at least 1 and rate_limit.{0}_per_sec above 0",
+                    name
+                )));
+            }
+        }
+        if limits.ws_max_rejected == 0 {
+            return invalid("rate_limit.ws_max_rejected must be at least 1");
+        }
+
+        let chat = &self.chat;
+        if chat.max_len == 0 {
+            return invalid("chat.max_len must be at least 1");
+        }
+        if chat.burst == 0 || !(chat.per_sec > 0.0 && chat.per_sec.is_finite()) {
+            return invalid("chat.burst must be at least 1 and chat.per_sec above 0");
+        }
+
+        let rules = &self.table;
+        if rules.max_seats == 0 || rules.max_seats > MAX_SEATS {
+            return Err(ConfigError::Invalid(format!(
+                "table.max_seats must be between 1 and {}",
+                MAX_SEATS
+            )));
+        }
+        if rules.decks == 0 {
+            return invalid("table.decks must be at least 1");
+        }
+        if !(2..=21).contains(&rules.dealer_stands_on) {
+            return invalid("table.dealer_stands_on must be between 2 and 21");
+        }
+        if rules.min_bet == 0 || rules.min_bet > rules.max_bet {
+            return invalid("table.min_bet must be at least 1 and no more than table.max_bet");
+        }
+        if rules.max_bet > MAX_BET_LIMIT {
+            return Err(ConfigError::Invalid(format!(
+                "table.max_bet must be at most {}",
+                MAX_BET_LIMIT
+            )));
+        }
+        if rules.max_spots == 0 || rules.max_spots > MAX_SPOTS_LIMIT {
+            return Err(ConfigError::Invalid(format!(
+                "table.max_spots must be between 1 and {}",
+                MAX_SPOTS_LIMIT
+            )));
+        }
+
+        if self.timers.write_timeout_secs == 0 {
+            return invalid("timers.write_timeout_secs must be at least 1");
+        }
+        let max_ttl = MAX_TOKEN_TTL.num_seconds() as u64;
+        if self.timers.session_ttl_secs == 0 || self.timers.session_ttl_secs > max_ttl {
+            return Err(ConfigError::Invalid(format!(
+                "timers.session_ttl_secs must be between 1 and {}",
+                max_ttl
+            )));
+        }
+        Ok(())
+    }
+
+    fn validate_database(&self) -> Result<(), ConfigError> {
+        let invalid = |msg: &str| Err(ConfigError::Invalid(msg.to_string()));
+
+        if self.database.pool_size == 0 {
+            return invalid("database.pool_size must be at least 1");
+        }
+        if let Some(path) = &self.database.ca_file {
+            if !self.database.tls {
+                return invalid("database.ca_file needs database.tls to be set");
+            }
+            if !path.is_file() {
+                return Err(ConfigError::Invalid(format!(
+                    "database.ca_file {} is not a file",
+                    path.display()
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_env<T>(var: &'static str) -> Result<Option<T>, ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    match env::var(var) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|e: T::Err| ConfigError::Env(var, e.to_string())),
+        Err(_) => Ok(None),
+    }
+}
@@ -0,0 +1,22 @@
+use tokio::sync::oneshot;
+use uuid::Uuid;
+
+use crate::blackjack::game::Balance;
+
+/// Reads and writes of player chips. They go through a single task, in
+/// order, so a balance saved when a player leaves a table is always seen
+/// by the load when they sit down again.
+#[derive(Debug)]
+pub enum Command {
+    LoadChips {
+        id: Uuid,
+        resp: oneshot::Sender<Option<u32>>,
+    },
+    SaveBalance {
+        balance: Balance,
+    },
+    /// Answers once every command sent before it has been handled.
+    Flush {
+        resp: oneshot::Sender<()>,
+    },
+}
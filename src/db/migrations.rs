@@ -0,0 +1,92 @@
+use bb8::Pool;
+use bb8_postgres::PostgresConnectionManager;
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+// Key for the advisory lock held while migrating, so that several
+// instances starting at once apply each migration exactly once.
+const MIGRATION_LOCK: i64 = 0x626c_6163_6b6a_6163;
+
+struct Migration {
+    version: i32,
+    name: &'static str,
+    sql: &'static str,
+}
+
+// Every migration, in the order they are applied. Append new ones; never
+// edit one that has shipped.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create_users",
+        sql: include_str!("../../migrations/0001_create_users.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "add_password_hash",
+        sql: include_str!("../../migrations/0002_add_password_hash.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "add_guest_accounts",
+        sql: include_str!("../../migrations/0003_add_guest_accounts.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "unique_username",
+        sql: include_str!("../../migrations/0004_unique_username.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "add_profiles",
+        sql: include_str!("../../migrations/0005_add_profiles.sql"),
+    },
+];
+
+/// Brings the schema up to date, applying each pending migration in its own
+/// transaction. Returns the versions that were applied.
+pub async fn run(
+    pool: &Pool<PostgresConnectionManager<MakeRustlsConnect>>,
+) -> Result<Vec<i32>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut conn = pool.get().await?;
+
+    conn.batch_execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INT PRIMARY KEY,
+            name TEXT NOT NULL,
+            applied_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+        )",
+    )
+    .await?;
+
+    conn.execute("SELECT pg_advisory_lock($1)", &[&MIGRATION_LOCK])
+        .await?;
+
+    let mut applied = Vec::new();
+    let result = async {
+        let row = conn
+            .query_one("SELECT COALESCE(MAX(version), 0) FROM schema_version", &[])
+            .await?;
+        let current: i32 = row.get(0);
+
+        for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+            let transaction = conn.transaction().await?;
+            transaction.batch_execute(migration.sql).await?;
+            transaction
+                .execute(
+                    "INSERT INTO schema_version (version, name) VALUES ($1, $2)",
+                    &[&migration.version, &migration.name],
+                )
+                .await?;
+            transaction.commit().await?;
+            applied.push(migration.version);
+        }
+        Ok::<_, tokio_postgres::Error>(())
+    }
+    .await;
+
+    conn.execute("SELECT pg_advisory_unlock($1)", &[&MIGRATION_LOCK])
+        .await?;
+    result?;
+
+    Ok(applied)
+}
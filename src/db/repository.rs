@@ -0,0 +1,622 @@
+use std::{collections::HashMap, fmt, sync::Mutex, time::Duration};
+
+use axum::async_trait;
+use bb8::Pool;
+use bb8_postgres::PostgresConnectionManager;
+use chrono::{DateTime, Utc};
+use tokio_postgres_rustls::MakeRustlsConnect;
+use uuid::Uuid;
+
+use crate::blackjack::game::Balance;
+use crate::db::user_data::{is_unique_violation, User, DELETED_PREFIX, USER_COLUMNS};
+
+// How often guest accounts nobody can sign in as any more are removed.
+pub const GUEST_SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug)]
+pub enum RepoError {
+    UsernameTaken,
+    Backend(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl fmt::Display for RepoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RepoError::UsernameTaken => write!(f, "username is already taken"),
+            RepoError::Backend(e) => write!(f, "storage error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RepoError {}
+
+impl From<tokio_postgres::Error> for RepoError {
+    fn from(e: tokio_postgres::Error) -> Self {
+        if is_unique_violation(&e) {
+            RepoError::UsernameTaken
+        } else {
+            RepoError::Backend(Box::new(e))
+        }
+    }
+}
+
+impl From<bb8::RunError<tokio_postgres::Error>> for RepoError {
+    fn from(e: bb8::RunError<tokio_postgres::Error>) -> Self {
+        RepoError::Backend(Box::new(e))
+    }
+}
+
+/// Changes to a profile. `None` leaves a field alone; for the optional
+/// fields `Some(None)` clears it.
+#[derive(Debug, Default)]
+pub struct ProfileUpdate {
+    pub username: Option<String>,
+    pub display_name: Option<Option<String>>,
+    pub avatar: Option<Option<String>>,
+}
+
+/// Name a deleted account is left with, unique and not tied to the person.
+fn deleted_name(id: Uuid) -> String {
+    format!("{}{}", DELETED_PREFIX, id.simple())
+}
+
+/// Storage for accounts and the chips and wins recorded against them.
+/// Names are unique regardless of case.
+#[async_trait]
+pub trait UserRepository: Send + Sync {
+    /// Registers an account, failing with `UsernameTaken` if the name is in use.
+    async fn create_user(
+        &self,
+        id: Uuid,
+        name: &str,
+        password_hash: &str,
+        chips: u32,
+    ) -> Result<User, RepoError>;
+
+    async fn create_guest(&self, id: Uuid, name: &str, chips: u32) -> Result<User, RepoError>;
+
+    /// Gives a guest account a name and password. Returns `None` if the
+    /// account does not exist or is not a guest.
+    async fn claim_guest(
+        &self,
+        id: Uuid,
+        name: &str,
+        password_hash: &str,
+    ) -> Result<Option<User>, RepoError>;
+
+    async fn find_by_id(&self, id: Uuid) -> Result<Option<User>, RepoError>;
+
+    /// Looks up several accounts at once. Unknown ids are left out.
+    async fn find_by_ids(&self, ids: &[Uuid]) -> Result<Vec<User>, RepoError>;
+
+    async fn find_by_name(&self, name: &str) -> Result<Option<User>, RepoError>;
+
+    /// Looks up an account along with its password hash, which guests lack.
+    async fn find_credentials(
+        &self,
+        name: &str,
+    ) -> Result<Option<(User, Option<String>)>, RepoError>;
+
+    /// Applies a profile change, failing with `UsernameTaken` if a new
+    /// username is in use. Returns `None` if the account does not exist.
+    async fn update_profile(
+        &self,
+        id: Uuid,
+        update: ProfileUpdate,
+    ) -> Result<Option<User>, RepoError>;
+
+    /// Anonymizes an account: its name, password and profile are wiped,
+    /// while the row stays so past results still add up. Returns whether
+    /// there was an account to delete.
+    async fn delete_user(&self, id: Uuid) -> Result<bool, RepoError>;
+
+    /// Removes guest accounts created before `created_before`, except those
+    /// in `keep`. Returns how many were removed.
+    async fn delete_expired_guests(
+        &self,
+        created_before: DateTime<Utc>,
+        keep: &[Uuid],
+    ) -> Result<u64, RepoError>;
+
+    async fn load_chips(&self, id: Uuid) -> Result<Option<u32>, RepoError>;
+
+    /// Stores a player's chips and adds their new wins.
+    async fn save_balance(&self, balance: &Balance) -> Result<(), RepoError>;
+
+    /// Checks that the storage can be reached.
+    async fn ping(&self) -> Result<(), RepoError>;
+
+    /// Connection pool usage, for storage that has a pool.
+    fn pool_state(&self) -> Option<bb8::State> {
+        None
+    }
+}
+
+pub struct PgUserRepository {
+    pool: Pool<PostgresConnectionManager<MakeRustlsConnect>>,
+}
+
+impl PgUserRepository {
+    pub fn new(pool: Pool<PostgresConnectionManager<MakeRustlsConnect>>) -> Self {
+        PgUserRepository { pool }
+    }
+}
+
+#[async_trait]
+impl UserRepository for PgUserRepository {
+    async fn create_user(
+        &self,
+        id: Uuid,
+        name: &str,
+        password_hash: &str,
+        chips: u32,
+    ) -> Result<User, RepoError> {
+        let conn = self.pool.get().await?;
+        let chips = i32::try_from(chips).unwrap_or(i32::MAX);
+
+        // One statement, so two signups racing for a name can't both succeed;
+        // the unique index on lower(username) decides
+        let row = conn
+            .query_opt(
+                &format!(
+                    "INSERT INTO users (id, username, password_hash, chips) \
+                     VALUES ($1, $2, $3, $4) \
+                     ON CONFLICT ((lower(username))) DO NOTHING RETURNING {}",
+                    USER_COLUMNS
+                ),
+                &[&id, &name, &password_hash, &chips],
+            )
+            .await?;
+
+        row.map(|row| User::from_row(&row))
+            .ok_or(RepoError::UsernameTaken)
+    }
+
+    async fn create_guest(&self, id: Uuid, name: &str, chips: u32) -> Result<User, RepoError> {
+        let conn = self.pool.get().await?;
+        let chips = i32::try_from(chips).unwrap_or(i32::MAX);
+        let row = conn
+            .query_one(
+                &format!(
+                    "INSERT INTO users (id, username, chips, is_guest) VALUES ($1, $2, $3, true) \
+                     RETURNING {}",
+                    USER_COLUMNS
+                ),
+                &[&id, &name, &chips],
+            )
+            .await?;
+        Ok(User::from_row(&row))
+    }
+
+    async fn claim_guest(
+        &self,
+        id: Uuid,
+        name: &str,
+        password_hash: &str,
+    ) -> Result<Option<User>, RepoError> {
+        let conn = self.pool.get().await?;
+        let row = conn
+            .query_opt(
+                &format!(
+                    "UPDATE users SET username = $2, password_hash = $3, is_guest = false \
+                     WHERE id = $1 AND is_guest AND deleted_at IS NULL RETURNING {}",
+                    USER_COLUMNS
+                ),
+                &[&id, &name, &password_hash],
+            )
+            .await?;
+        Ok(row.map(|row| User::from_row(&row)))
+    }
+
+    async fn find_by_id(&self, id: Uuid) -> Result<Option<User>, RepoError> {
+        let conn = self.pool.get().await?;
+        let row = conn
+            .query_opt(
+                &format!(
+                    "SELECT {} FROM users WHERE id = $1 AND deleted_at IS NULL",
+                    USER_COLUMNS
+                ),
+                &[&id],
+            )
+            .await?;
+        Ok(row.map(|row| User::from_row(&row)))
+    }
+
+    async fn find_by_ids(&self, ids: &[Uuid]) -> Result<Vec<User>, RepoError> {
+        let conn = self.pool.get().await?;
+        let rows = conn
+            .query(
+                &format!(
+                    "SELECT {} FROM users WHERE id = ANY($1) AND deleted_at IS NULL",
+                    USER_COLUMNS
+                ),
+                &[&ids],
+            )
+            .await?;
+        Ok(rows.iter().map(User::from_row).collect())
+    }
+
+    async fn find_by_name(&self, name: &str) -> Result<Option<User>, RepoError> {
+        let conn = self.pool.get().await?;
+        let row = conn
+            .query_opt(
+                &format!(
+                    "SELECT {} FROM users \
+                     WHERE lower(username) = lower($1) AND deleted_at IS NULL",
+                    USER_COLUMNS
+                ),
+                &[&name],
+            )
+            .await?;
+        Ok(row.map(|row| User::from_row(&row)))
+    }
+
+    async fn find_credentials(
+        &self,
+        name: &str,
+    ) -> Result<Option<(User, Option<String>)>, RepoError> {
+        let conn = self.pool.get().await?;
+        let row = conn
+            .query_opt(
+                &format!(
+                    "SELECT {}, password_hash FROM users \
+                     WHERE lower(username) = lower($1) AND deleted_at IS NULL",
+                    USER_COLUMNS
+                ),
+                &[&name],
+            )
+            .await?;
+        Ok(row.map(|row| (User::from_row(&row), row.get(8))))
+    }
+
+    async fn update_profile(
+        &self,
+        id: Uuid,
+        update: ProfileUpdate,
+    ) -> Result<Option<User>, RepoError> {
+        let conn = self.pool.get().await?;
+        let set_display_name = update.display_name.is_some();
+        let set_avatar = update.avatar.is_some();
+        let row = conn
+            .query_opt(
+                &format!(
+                    "UPDATE users SET \
+                     username = COALESCE($2, username), \
+                     display_name = CASE WHEN $3 THEN $4 ELSE display_name END, \
+                     avatar = CASE WHEN $5 THEN $6 ELSE avatar END \
+                     WHERE id = $1 AND deleted_at IS NULL RETURNING {}",
+                    USER_COLUMNS
+                ),
+                &[
+                    &id,
+                    &update.username,
+                    &set_display_name,
+                    &update.display_name.flatten(),
+                    &set_avatar,
+                    &update.avatar.flatten(),
+                ],
+            )
+            .await?;
+        Ok(row.map(|row| User::from_row(&row)))
+    }
+
+    async fn delete_user(&self, id: Uuid) -> Result<bool, RepoError> {
+        let conn = self.pool.get().await?;
+        let rows = conn
+            .execute(
+                "UPDATE users SET username = $2, password_hash = NULL, display_name = NULL, \
+                 avatar = NULL, is_guest = false, deleted_at = CURRENT_TIMESTAMP \
+                 WHERE id = $1 AND deleted_at IS NULL",
+                &[&id, &deleted_name(id)],
+            )
+            .await?;
+        Ok(rows != 0)
+    }
+
+    async fn delete_expired_guests(
+        &self,
+        created_before: DateTime<Utc>,
+        keep: &[Uuid],
+    ) -> Result<u64, RepoError> {
+        let conn = self.pool.get().await?;
+        let rows = conn
+            .execute(
+                "DELETE FROM users \
+                 WHERE is_guest AND created_at < $1 AND NOT (id = ANY($2))",
+                &[&created_before, &keep],
+            )
+            .await?;
+        Ok(rows)
+    }
+
+    async fn load_chips(&self, id: Uuid) -> Result<Option<u32>, RepoError> {
+        let conn = self.pool.get().await?;
+        let row = conn
+            .query_opt(
+                "SELECT chips FROM users WHERE id = $1 AND deleted_at IS NULL",
+                &[&id],
+            )
+            .await?;
+        Ok(row.map(|row| row.get::<_, i32>(0).max(0) as u32))
+    }
+
+    async fn save_balance(&self, balance: &Balance) -> Result<(), RepoError> {
+        let conn = self.pool.get().await?;
+        let chips = i32::try_from(balance.chips).unwrap_or(i32::MAX);
+        let wins = i32::try_from(balance.wins).unwrap_or(i32::MAX);
+        conn.execute(
+            "UPDATE users SET chips = $2, wins = wins + $3 \
+             WHERE id = $1 AND deleted_at IS NULL",
+            &[&balance.id, &chips, &wins],
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<(), RepoError> {
+        let conn = self.pool.get().await?;
+        conn.execute("SELECT 1", &[]).await?;
+        Ok(())
+    }
+
+    fn pool_state(&self) -> Option<bb8::State> {
+        Some(self.pool.state())
+    }
+}
+
+struct StoredUser {
+    user: User,
+    password_hash: Option<String>,
+    deleted: bool,
+}
+
+/// Keeps accounts in memory, for running without a database. Nothing
+/// survives a restart.
+#[derive(Default)]
+pub struct MemoryUserRepository {
+    users: Mutex<HashMap<Uuid, StoredUser>>,
+}
+
+impl MemoryUserRepository {
+    pub fn new() -> Self {
+        MemoryUserRepository::default()
+    }
+
+    fn get(users: &HashMap<Uuid, StoredUser>, id: Uuid) -> Option<&StoredUser> {
+        users.get(&id).filter(|stored| !stored.deleted)
+    }
+
+    fn name_taken(users: &HashMap<Uuid, StoredUser>, name: &str, except: Uuid) -> bool {
+        let name = name.to_lowercase();
+        users
+            .values()
+            .any(|stored| stored.user._id != except && stored.user.name.to_lowercase() == name)
+    }
+
+    fn insert(
+        &self,
+        id: Uuid,
+        name: &str,
+        password_hash: Option<String>,
+        chips: u32,
+    ) -> Result<User, RepoError> {
+        let mut users = self.users.lock().unwrap();
+        if users.contains_key(&id) || Self::name_taken(&users, name, id) {
+            return Err(RepoError::UsernameTaken);
+        }
+
+        let user = User {
+            _id: id,
+            name: name.to_string(),
+            created_at: Utc::now(),
+            wins: 0,
+            chips: i32::try_from(chips).unwrap_or(i32::MAX),
+            is_guest: password_hash.is_none(),
+            display_name: None,
+            avatar: None,
+        };
+        users.insert(
+            id,
+            StoredUser {
+                user: user.clone(),
+                password_hash,
+                deleted: false,
+            },
+        );
+        Ok(user)
+    }
+
+    fn find(&self, name: &str) -> Option<(User, Option<String>)> {
+        let name = name.to_lowercase();
+        self.users
+            .lock()
+            .unwrap()
+            .values()
+            .find(|stored| !stored.deleted && stored.user.name.to_lowercase() == name)
+            .map(|stored| (stored.user.clone(), stored.password_hash.clone()))
+    }
+}
+
+#[async_trait]
+impl UserRepository for MemoryUserRepository {
+    async fn create_user(
+        &self,
+        id: Uuid,
+        name: &str,
+        password_hash: &str,
+        chips: u32,
+    ) -> Result<User, RepoError> {
+        self.insert(id, name, Some(password_hash.to_string()), chips)
+    }
+
+    async fn create_guest(&self, id: Uuid, name: &str, chips: u32) -> Result<User, RepoError> {
+        self.insert(id, name, None, chips)
+    }
+
+    async fn claim_guest(
+        &self,
+        id: Uuid,
+        name: &str,
+        password_hash: &str,
+    ) -> Result<Option<User>, RepoError> {
+        let mut users = self.users.lock().unwrap();
+        if !Self::get(&users, id).is_some_and(|stored| stored.user.is_guest) {
+            return Ok(None);
+        }
+        if Self::name_taken(&users, name, id) {
+            return Err(RepoError::UsernameTaken);
+        }
+
+        let stored = users.get_mut(&id).unwrap();
+        stored.user.name = name.to_string();
+        stored.user.is_guest = false;
+        stored.password_hash = Some(password_hash.to_string());
+        Ok(Some(stored.user.clone()))
+    }
+
+    async fn find_by_id(&self, id: Uuid) -> Result<Option<User>, RepoError> {
+        let users = self.users.lock().unwrap();
+        Ok(Self::get(&users, id).map(|stored| stored.user.clone()))
+    }
+
+    async fn find_by_ids(&self, ids: &[Uuid]) -> Result<Vec<User>, RepoError> {
+        let users = self.users.lock().unwrap();
+        Ok(ids
+            .iter()
+            .filter_map(|id| Self::get(&users, *id))
+            .map(|stored| stored.user.clone())
+            .collect())
+    }
+
+    async fn find_by_name(&self, name: &str) -> Result<Option<User>, RepoError> {
+        Ok(self.find(name).map(|(user, _)| user))
+    }
+
+    async fn find_credentials(
+        &self,
+        name: &str,
+    ) -> Result<Option<(User, Option<String>)>, RepoError> {
+        Ok(self.find(name))
+    }
+
+    async fn update_profile(
+        &self,
+        id: Uuid,
+        update: ProfileUpdate,
+    ) -> Result<Option<User>, RepoError> {
+        let mut users = self.users.lock().unwrap();
+        if Self::get(&users, id).is_none() {
+            return Ok(None);
+        }
+        if let Some(name) = &update.username {
+            if Self::name_taken(&users, name, id) {
+                return Err(RepoError::UsernameTaken);
+            }
+        }
+
+        let stored = users.get_mut(&id).unwrap();
+        if let Some(name) = update.username {
+            stored.user.name = name;
+        }
+        if let Some(display_name) = update.display_name {
+            stored.user.display_name = display_name;
+        }
+        if let Some(avatar) = update.avatar {
+            stored.user.avatar = avatar;
+        }
+        Ok(Some(stored.user.clone()))
+    }
+
+    async fn delete_user(&self, id: Uuid) -> Result<bool, RepoError> {
+        let mut users = self.users.lock().unwrap();
+        let Some(stored) = users.get_mut(&id).filter(|stored| !stored.deleted) else {
+            return Ok(false);
+        };
+        stored.user.name = deleted_name(id);
+        stored.user.display_name = None;
+        stored.user.avatar = None;
+        stored.user.is_guest = false;
+        stored.password_hash = None;
+        stored.deleted = true;
+        Ok(true)
+    }
+
+    async fn delete_expired_guests(
+        &self,
+        created_before: DateTime<Utc>,
+        keep: &[Uuid],
+    ) -> Result<u64, RepoError> {
+        let mut users = self.users.lock().unwrap();
+        let before = users.len();
+        users.retain(|id, stored| {
+            !stored.user.is_guest || stored.user.created_at >= created_before || keep.contains(id)
+        });
+        Ok((before - users.len()) as u64)
+    }
+
+    async fn load_chips(&self, id: Uuid) -> Result<Option<u32>, RepoError> {
+        let users = self.users.lock().unwrap();
+        Ok(Self::get(&users, id).map(|stored| stored.user.chips.max(0) as u32))
+    }
+
+    async fn save_balance(&self, balance: &Balance) -> Result<(), RepoError> {
+        let mut users = self.users.lock().unwrap();
+        if let Some(stored) = users.get_mut(&balance.id).filter(|stored| !stored.deleted) {
+            stored.user.chips = i32::try_from(balance.chips).unwrap_or(i32::MAX);
+            stored.user.wins = stored
+                .user
+                .wins
+                .saturating_add(i32::try_from(balance.wins).unwrap_or(i32::MAX));
+        }
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<(), RepoError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn expired_guests_are_removed_unless_kept() {
+        let repo = MemoryUserRepository::new();
+        let (expired, connected, claimed, user) = (
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+        );
+        repo.create_guest(expired, "guest_a", 1000).await.unwrap();
+        repo.create_guest(connected, "guest_b", 1000).await.unwrap();
+        repo.create_guest(claimed, "guest_c", 1000).await.unwrap();
+        repo.claim_guest(claimed, "carol", "hash").await.unwrap();
+        repo.create_user(user, "alice", "hash", 1000).await.unwrap();
+
+        // Nothing was created before an hour ago
+        let an_hour_ago = Utc::now() - Duration::hours(1);
+        assert_eq!(
+            repo.delete_expired_guests(an_hour_ago, &[]).await.unwrap(),
+            0
+        );
+
+        let later = Utc::now() + Duration::seconds(1);
+        let removed = repo
+            .delete_expired_guests(later, &[connected])
+            .await
+            .unwrap();
+        assert_eq!(removed, 1);
+        assert!(repo.find_by_id(expired).await.unwrap().is_none());
+        for id in [connected, claimed, user] {
+            assert!(repo.find_by_id(id).await.unwrap().is_some());
+        }
+        // The name is free again
+        repo.create_guest(Uuid::new_v4(), "guest_a", 1000)
+            .await
+            .unwrap();
+    }
+}
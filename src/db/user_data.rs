@@ -1,16 +1,251 @@
+use std::fmt;
+
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use tokio_postgres::{error::SqlState, Row};
+use utoipa::{IntoParams, ToSchema};
 use uuid::Uuid;
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+// Columns read into a `User`, in the order `User::from_row` expects.
+pub const USER_COLUMNS: &str =
+    "id, username, created_at, wins, chips, is_guest, display_name, avatar";
+
+// Bounds on username length, in characters.
+pub const MIN_USERNAME_LEN: usize = 3;
+pub const MAX_USERNAME_LEN: usize = 20;
+
+// Prefixes of generated guest names and of the names deleted accounts are
+// left with, which registered users may not take.
+pub const GUEST_PREFIX: &str = "guest_";
+pub const DELETED_PREFIX: &str = "deleted_";
+
+// Most ids accepted by one batch lookup.
+pub const MAX_LOOKUP_IDS: usize = 100;
+
+// Longest display name accepted, in characters.
+pub const MAX_DISPLAY_NAME_LEN: usize = 32;
+
+// Avatars a user can pick from; the client ships the matching images.
+pub const AVATARS: &[&str] = &[
+    "ace", "king", "queen", "jack", "joker", "chip", "dice", "clover",
+];
+
+// Names that could be mistaken for staff or the game itself, and the
+// static routes under `/user/` that would hide a profile of the same name.
+// Compared case-insensitively.
+const RESERVED_USERNAMES: &[&str] = &[
+    "admin",
+    "administrator",
+    "claim",
+    "create",
+    "dealer",
+    "guest",
+    "house",
+    "login",
+    "moderator",
+    "root",
+    "support",
+    "system",
+];
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum UsernameError {
+    TooShort,
+    TooLong,
+    InvalidCharacter,
+    Reserved,
+}
+
+impl fmt::Display for UsernameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UsernameError::TooShort => write!(
+                f,
+                "username must be at least {} characters",
+                MIN_USERNAME_LEN
+            ),
+            UsernameError::TooLong => write!(
+                f,
+                "username must be at most {} characters",
+                MAX_USERNAME_LEN
+            ),
+            UsernameError::InvalidCharacter => write!(
+                f,
+                "username must start with a letter and contain only letters, digits, '_' and '-'"
+            ),
+            UsernameError::Reserved => write!(f, "username is reserved"),
+        }
+    }
+}
+
+impl std::error::Error for UsernameError {}
+
+/// Checks a name chosen by a user. Uniqueness is left to the database.
+pub fn validate_username(name: &str) -> Result<(), UsernameError> {
+    let len = name.chars().count();
+    if len < MIN_USERNAME_LEN {
+        return Err(UsernameError::TooShort);
+    }
+    if len > MAX_USERNAME_LEN {
+        return Err(UsernameError::TooLong);
+    }
+
+    let mut chars = name.chars();
+    let starts_with_letter = chars.next().is_some_and(|c| c.is_ascii_alphabetic());
+    if !starts_with_letter || !chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        return Err(UsernameError::InvalidCharacter);
+    }
+
+    let lower = name.to_ascii_lowercase();
+    if lower.starts_with(GUEST_PREFIX)
+        || lower.starts_with(DELETED_PREFIX)
+        || RESERVED_USERNAMES.contains(&lower.as_str())
+    {
+        return Err(UsernameError::Reserved);
+    }
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ProfileError {
+    DisplayNameTooLong,
+    DisplayNameInvalid,
+    UnknownAvatar,
+}
+
+impl fmt::Display for ProfileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProfileError::DisplayNameTooLong => write!(
+                f,
+                "display name must be at most {} characters",
+                MAX_DISPLAY_NAME_LEN
+            ),
+            ProfileError::DisplayNameInvalid => {
+                write!(f, "display name contains invalid characters")
+            }
+            ProfileError::UnknownAvatar => write!(f, "unknown avatar"),
+        }
+    }
+}
+
+impl std::error::Error for ProfileError {}
+
+/// Checks a display name, which unlike a username may contain spaces and
+/// any printable characters and need not be unique.
+pub fn validate_display_name(name: &str) -> Result<(), ProfileError> {
+    if name.chars().count() > MAX_DISPLAY_NAME_LEN {
+        return Err(ProfileError::DisplayNameTooLong);
+    }
+    if name.chars().any(char::is_control) {
+        return Err(ProfileError::DisplayNameInvalid);
+    }
+    Ok(())
+}
+
+pub fn validate_avatar(avatar: &str) -> Result<(), ProfileError> {
+    if AVATARS.contains(&avatar) {
+        Ok(())
+    } else {
+        Err(ProfileError::UnknownAvatar)
+    }
+}
+
+/// Whether a statement failed on a unique constraint, such as the
+/// case-insensitive username index.
+pub fn is_unique_violation(e: &tokio_postgres::Error) -> bool {
+    e.code() == Some(&SqlState::UNIQUE_VIOLATION)
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
 pub struct User {
     pub _id: Uuid,
     pub name: String,
     pub created_at: DateTime<Utc>,
     pub wins: i32,
+    pub chips: i32,
+    pub is_guest: bool,
+    pub display_name: Option<String>,
+    pub avatar: Option<String>,
 }
 
-#[derive(Deserialize)]
+impl User {
+    pub fn from_row(row: &Row) -> Self {
+        User {
+            _id: row.get(0),
+            name: row.get(1),
+            created_at: row.get(2),
+            wins: row.get(3),
+            chips: row.get(4),
+            is_guest: row.get(5),
+            display_name: row.get(6),
+            avatar: row.get(7),
+        }
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
 pub struct PostUserJson {
     pub name: String,
+    pub password: String,
+}
+
+/// What anyone may see about a user: no chip count and no account state
+/// beyond whether it is a guest.
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct PublicUser {
+    pub _id: Uuid,
+    pub name: String,
+    pub display_name: Option<String>,
+    pub avatar: Option<String>,
+    pub wins: i32,
+    pub is_guest: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<User> for PublicUser {
+    fn from(user: User) -> Self {
+        PublicUser {
+            _id: user._id,
+            name: user.name,
+            display_name: user.display_name,
+            avatar: user.avatar,
+            wins: user.wins,
+            is_guest: user.is_guest,
+            created_at: user.created_at,
+        }
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct LookupJson {
+    pub ids: Vec<Uuid>,
+}
+
+/// Changes to a profile. Absent fields are left alone; an empty display
+/// name or avatar clears it.
+#[derive(Deserialize, ToSchema)]
+pub struct PatchUserJson {
+    pub username: Option<String>,
+    pub display_name: Option<String>,
+    pub avatar: Option<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct LoginJson {
+    pub name: String,
+    pub password: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct LoginResponse {
+    pub token: String,
+    pub user: User,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct WsQuery {
+    /// Session token, for clients that cannot set `Authorization`.
+    pub token: Option<String>,
 }
@@ -0,0 +1,123 @@
+use std::{collections::BTreeMap, sync::atomic::Ordering, sync::Arc, time::Duration};
+
+use axum::{extract::State, http::StatusCode, Json};
+use serde::Serialize;
+use tokio::sync::{mpsc, oneshot};
+use utoipa::ToSchema;
+
+use crate::{blackjack::game, db::ledger, websocket_manager, AppState};
+
+// How long a task or the database gets to answer a readiness check.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, ToSchema)]
+pub struct Health {
+    status: &'static str,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Check {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Check {
+    fn ok() -> Self {
+        Check {
+            ok: true,
+            error: None,
+        }
+    }
+
+    fn failed(error: impl ToString) -> Self {
+        Check {
+            ok: false,
+            error: Some(error.to_string()),
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Readiness {
+    ready: bool,
+    draining: bool,
+    checks: BTreeMap<&'static str, Check>,
+}
+
+/// The process is up and serving requests.
+#[utoipa::path(get, path = "/healthz", tag = "health", responses((status = 200, body = Health)))]
+pub async fn healthz() -> Json<Health> {
+    Json(Health { status: "ok" })
+}
+
+/// Whether the server can take traffic: the database answers, the game,
+/// manager and ledger tasks are alive and it is not shutting down.
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "health",
+    responses(
+        (status = 200, description = "Ready for traffic", body = Readiness),
+        (status = 503, description = "Not ready", body = Readiness),
+    )
+)]
+pub async fn readyz(State(app_state): State<Arc<AppState>>) -> (StatusCode, Json<Readiness>) {
+    let database = async {
+        match tokio::time::timeout(CHECK_TIMEOUT, app_state.users.ping()).await {
+            Ok(Ok(())) => Check::ok(),
+            Ok(Err(e)) => Check::failed(e),
+            Err(_) => Check::failed("timed out"),
+        }
+    };
+    let (database, game, websocket_manager, ledger) = tokio::join!(
+        database,
+        ping(&app_state.game_send, |resp| game::Command::Ping { resp }),
+        ping(&app_state.wm_send, |resp| {
+            websocket_manager::Command::Ping { resp }
+        }),
+        ping(&app_state.ledger_send, |resp| ledger::Command::Flush {
+            resp
+        }),
+    );
+
+    let draining = app_state.draining.load(Ordering::Relaxed);
+    let checks = BTreeMap::from([
+        ("database", database),
+        ("game", game),
+        ("websocket_manager", websocket_manager),
+        ("ledger", ledger),
+    ]);
+    let ready = !draining && checks.values().all(|check| check.ok);
+
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(Readiness {
+            ready,
+            draining,
+            checks,
+        }),
+    )
+}
+
+/// Sends a command carrying a reply channel to an actor task and waits for
+/// the answer. A closed channel means the task has died.
+async fn ping<C>(send: &mpsc::Sender<C>, command: impl FnOnce(oneshot::Sender<()>) -> C) -> Check {
+    let (resp, answer) = oneshot::channel();
+    let sent = async {
+        send.send(command(resp))
+            .await
+            .map_err(|_| "task is not running")?;
+        answer.await.map_err(|_| "task is not running")
+    };
+    match tokio::time::timeout(CHECK_TIMEOUT, sent).await {
+        Ok(Ok(())) => Check::ok(),
+        Ok(Err(e)) => Check::failed(e),
+        Err(_) => Check::failed("timed out"),
+    }
+}
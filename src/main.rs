@@ -1,332 +1,1283 @@
+mod api_doc;
+mod auth;
 mod blackjack {
     pub mod game;
 }
 mod card;
+mod chat;
+mod config;
+mod error;
+mod health;
+mod metrics;
 mod db {
+    pub mod ledger;
+    pub mod migrations;
+    pub mod repository;
     pub mod user_data;
 }
+mod rate_limit;
+mod tls;
 mod websocket_manager;
 
 use anyhow::Result;
 use axum::{
-    body::Body,
+    body::{Body, Bytes},
     debug_handler,
     extract::{
+        ws::rejection::WebSocketUpgradeRejection,
         ws::{Message, WebSocket, WebSocketUpgrade},
-        ConnectInfo, Json, Path, State,
+        ConnectInfo, Json, State,
     },
     http::{header, StatusCode},
+    middleware,
     response::{IntoResponse, Response},
     routing::{get, post},
     Router,
 };
-use axum_extra::TypedHeader;
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
+};
+use axum_server::tls_rustls::RustlsConfig;
 use bb8::Pool;
 use bb8_postgres::PostgresConnectionManager;
-use blackjack::game::Blackjack;
-use db::user_data;
+use blackjack::game::{self, Action, Blackjack, Departure};
+use card::{CardFormat, CardImage, CardQuery};
+use chat::{ChatFilter, ChatLimiter, ChatScope};
+use db::ledger;
 use futures_util::StreamExt;
-use http::{header::CONTENT_TYPE, Method};
-use std::{env, sync::Arc};
+use http::{
+    header::{AUTHORIZATION, CONTENT_TYPE, COOKIE, ORIGIN},
+    HeaderMap, Method, Request,
+};
+use rate_limit::{IpRateLimiter, MessageLimiter, Verdict};
+use std::{
+    env,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 use std::{net::SocketAddr, ops::ControlFlow};
 use tokio::fs;
-use tokio_postgres::NoTls;
+use tokio_postgres::config::SslMode;
 use tokio_util::io::ReaderStream;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 use tower_http::{
-    cors::{Any, CorsLayer},
-    trace::{DefaultMakeSpan, TraceLayer},
+    cors::{AllowOrigin, CorsLayer},
+    sensitive_headers::SetSensitiveRequestHeadersLayer,
+    trace::TraceLayer,
 };
 use uuid::Uuid;
-use websocket_manager::WebSocketManager;
+use websocket_manager::{ConnKey, RecvWS, SendWS, WebSocketManager};
 
+use tracing::{debug, debug_span, error, info, info_span, trace, warn, Instrument};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-use crate::db::user_data::{PostUserJson, User};
+use crate::{
+    auth::AuthUser,
+    config::{Config, LogFormat},
+    db::{
+        repository::{
+            MemoryUserRepository, PgUserRepository, ProfileUpdate, UserRepository,
+            GUEST_SWEEP_INTERVAL,
+        },
+        user_data::{
+            validate_avatar, validate_display_name, validate_username, LoginJson, LoginResponse,
+            LookupJson, PatchUserJson, PostUserJson, PublicUser, User, WsQuery, GUEST_PREFIX,
+            MAX_LOOKUP_IDS,
+        },
+    },
+    error::{ApiError, ApiJson, ApiPath, ApiQuery},
+};
 
 struct AppState {
-    db: DB,
+    config: Config,
+    users: Arc<dyn UserRepository>,
+    keys: auth::Keys,
     wm_send: tokio::sync::mpsc::Sender<websocket_manager::Command>,
+    game_send: tokio::sync::mpsc::Sender<game::Command>,
+    ledger_send: tokio::sync::mpsc::Sender<ledger::Command>,
+    chat_filter: ChatFilter,
+    card_cache: card::PngCache,
+    // Set once shutdown has begun, so the server reports itself not ready
+    draining: AtomicBool,
+    // Every connection's writer task
+    writers: TaskTracker,
 }
 
-struct DB {
-    db_pool: Pool<PostgresConnectionManager<NoTls>>,
-}
+/// The image of a card, as the SVG it is drawn in or rendered to a PNG of
+/// the requested width for clients that cannot show SVG.
+#[utoipa::path(
+    get,
+    path = "/card/{value}/{suit}",
+    tag = "cards",
+    params(
+        ("value" = String, Path, description = "`ace`, `2` to `10`, `jack`, `queen` or `king`, or the letters `a`, `t`, `j`, `q` and `k`; `joker` for a joker"),
+        ("suit" = String, Path, description = "`clubs`, `diamonds`, `hearts` or `spades`, or their initials; `red` or `black` for a joker"),
+        CardQuery,
+    ),
+    responses(
+        (status = 200, description = "The card image", content_type = ["image/svg+xml", "image/png"]),
+        (status = 400, description = "Unknown value, suit or format, or a width or variant out of range", body = ErrorBody),
+        (status = 404, description = "No image for the card or variant", body = ErrorBody),
+    )
+)]
+async fn get_card(
+    State(app_state): State<Arc<AppState>>,
+    ApiPath((value, suit)): ApiPath<(String, String)>,
+    ApiQuery(query): ApiQuery<CardQuery>,
+) -> Result<Response, ApiError> {
+    let image = CardImage::parse(&value, &suit)?;
+    let variant = query.variant.unwrap_or(1);
+    if !(1..=card::MAX_VARIANT).contains(&variant) {
+        return Err(ApiError::BadRequest(format!(
+            "variant must be between 1 and {}",
+            card::MAX_VARIANT
+        )));
+    }
+    let file_name = image.file_name(variant);
+
+    let path = app_state
+        .config
+        .server
+        .asset_dir
+        .join(format!("cards/{}.svg", file_name));
+    let not_found = || ApiError::NotFound("card image not found".to_string());
+
+    if query.format.unwrap_or_default() == CardFormat::Svg {
+        if query.width.is_some() {
+            return Err(ApiError::BadRequest(
+                "width only applies to format=png".to_string(),
+            ));
+        }
+        let file = fs::File::open(path).await.map_err(|_| not_found())?;
 
-async fn get_card(Path((value, suit)): Path<(String, String)>) -> impl IntoResponse {
-    let card_result = card::get_card_file(value, suit);
+        let stream = ReaderStream::new(file);
 
-    let file_name: String = match card_result {
-        Ok(name) => name,
-        Err(_) => return Err(StatusCode::BAD_REQUEST).into(),
-    };
+        let body = Body::from_stream(stream);
 
-    let file = match fs::File::open(&String::from(format!("assets/cards/{}.svg", file_name))).await
-    {
-        Ok(file) => file,
-        Err(_) => return Err(StatusCode::NOT_FOUND).into(),
+        let headers = [(header::CONTENT_TYPE, "image/svg+xml")];
+
+        return Ok((headers, body).into_response());
+    }
+
+    let width = query.width.unwrap_or(card::DEFAULT_PNG_WIDTH);
+    if !(card::MIN_PNG_WIDTH..=card::MAX_PNG_WIDTH).contains(&width) {
+        return Err(ApiError::BadRequest(format!(
+            "width must be between {} and {}",
+            card::MIN_PNG_WIDTH,
+            card::MAX_PNG_WIDTH
+        )));
+    }
+
+    let png = match app_state.card_cache.get(&file_name, width) {
+        Some(png) => png,
+        None => {
+            let svg = fs::read(path).await.map_err(|_| not_found())?;
+            // Rendering is CPU bound, so keep it off the async workers
+            let png = tokio::task::spawn_blocking(move || card::render_png(&svg, width))
+                .await
+                .map_err(ApiError::internal)?
+                .map_err(ApiError::internal)?;
+            let png = Bytes::from(png);
+            app_state.card_cache.insert(file_name, width, png.clone());
+            png
+        }
     };
 
-    let stream = ReaderStream::new(file);
+    let headers = [(header::CONTENT_TYPE, "image/png")];
 
-    let body = Body::from_stream(stream);
+    Ok((headers, png).into_response())
+}
 
-    let headers = [(header::CONTENT_TYPE, "image/svg+xml")];
+/// Checks the username and password an account is to be created with and
+/// hashes the password.
+async fn check_credentials(payload: &PostUserJson) -> Result<String, ApiError> {
+    validate_username(&payload.name)?;
 
-    Ok((headers, body))
+    if payload.password.chars().count() < auth::MIN_PASSWORD_LEN {
+        return Err(ApiError::BadRequest(format!(
+            "password must be at least {} characters",
+            auth::MIN_PASSWORD_LEN
+        )));
+    }
+
+    // Hashing is deliberately slow, so keep it off the async workers
+    let password = payload.password.clone();
+    tokio::task::spawn_blocking(move || auth::hash_password(&password))
+        .await
+        .map_err(ApiError::internal)?
+        .map_err(ApiError::internal)
 }
 
+/// Registers an account with a password.
+#[utoipa::path(
+    post,
+    path = "/user/create",
+    tag = "users",
+    request_body = PostUserJson,
+    responses(
+        (status = 200, description = "The new account", body = User),
+        (status = 400, description = "Invalid username or password", body = ErrorBody),
+        (status = 409, description = "Username taken", body = ErrorBody),
+        (status = 429, description = "Too many accounts created", body = ErrorBody),
+    )
+)]
 #[debug_handler]
 async fn add_user(
     State(app_state): State<Arc<AppState>>,
-    Json(payload): Json<PostUserJson>,
-) -> Result<Json<User>, (StatusCode, String)> {
-    let user_name = payload.name;
-
-    let mut conn = app_state
-        .as_ref()
-        .db
-        .db_pool
-        .get()
-        .await
-        .map_err(internal_error)?;
+    ApiJson(payload): ApiJson<PostUserJson>,
+) -> Result<Json<User>, ApiError> {
+    let password_hash = check_credentials(&payload).await?;
+
+    let user = app_state
+        .users
+        .create_user(
+            Uuid::new_v4(),
+            &payload.name,
+            &password_hash,
+            game::STARTING_CHIPS,
+        )
+        .await?;
+
+    Ok(Json(user))
+}
 
+/// Creates a guest account and signs in as it. Unless claimed while its
+/// token is valid, the account is removed some time after the token expires.
+#[utoipa::path(
+    post,
+    path = "/user/guest",
+    tag = "users",
+    responses(
+        (status = 200, description = "The guest account and its token", body = LoginResponse),
+        (status = 429, description = "Too many accounts created", body = ErrorBody),
+    )
+)]
+async fn add_guest(
+    State(app_state): State<Arc<AppState>>,
+) -> Result<Json<LoginResponse>, ApiError> {
     let uuid = Uuid::new_v4();
+    // The whole id, so guest names can never collide
+    let user_name = format!("{}{}", GUEST_PREFIX, uuid.simple());
 
-    let rows = conn
-        .execute("SELECT id FROM users WHERE username = $1", &[&user_name])
-        .await;
+    let user = app_state
+        .users
+        .create_guest(uuid, &user_name, game::STARTING_CHIPS)
+        .await?;
+
+    let token = app_state
+        .keys
+        .issue_token(user._id, user.name.clone())
+        .map_err(ApiError::internal)?;
+
+    Ok(Json(LoginResponse { token, user }))
+}
+
+/// Turns the caller's guest account into a full account, keeping its id and
+/// with it the chips and wins it has built up.
+#[utoipa::path(
+    post,
+    path = "/user/claim",
+    tag = "users",
+    request_body = PostUserJson,
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "The claimed account and a new token", body = LoginResponse),
+        (status = 400, description = "Invalid username or password", body = ErrorBody),
+        (status = 401, description = "Missing or invalid token", body = ErrorBody),
+        (status = 409, description = "Username taken or not a guest", body = ErrorBody),
+    )
+)]
+async fn claim_guest(
+    State(app_state): State<Arc<AppState>>,
+    AuthUser(claims): AuthUser,
+    ApiJson(payload): ApiJson<PostUserJson>,
+) -> Result<Json<LoginResponse>, ApiError> {
+    let password_hash = check_credentials(&payload).await?;
+
+    let user = app_state
+        .users
+        .claim_guest(claims.sub, &payload.name, &password_hash)
+        .await?
+        .ok_or_else(|| ApiError::Conflict("account is not a guest account".to_string()))?;
+
+    rename_connections(&app_state, &user).await;
+
+    // The name is part of the token, so issue a fresh one
+    let token = app_state
+        .keys
+        .issue_token(user._id, user.name.clone())
+        .map_err(ApiError::internal)?;
+
+    Ok(Json(LoginResponse { token, user }))
+}
+
+/// Exchanges a username and password for a session token.
+#[utoipa::path(
+    post,
+    path = "/user/login",
+    tag = "users",
+    request_body = LoginJson,
+    responses(
+        (status = 200, description = "The account and its token", body = LoginResponse),
+        (status = 401, description = "Wrong username or password", body = ErrorBody),
+    )
+)]
+async fn login(
+    State(app_state): State<Arc<AppState>>,
+    ApiJson(payload): ApiJson<LoginJson>,
+) -> Result<Json<LoginResponse>, ApiError> {
+    let found = app_state.users.find_credentials(&payload.name).await?;
 
-    if rows.map_err(internal_error)? != 0 {
-        return Err((
-            StatusCode::CONFLICT,
-            "username was found in the database".to_string(),
+    let unauthorized = || ApiError::Unauthorized("invalid username or password".to_string());
+
+    let (user, password_hash) = found.ok_or_else(unauthorized)?;
+    let password_hash = password_hash.ok_or_else(unauthorized)?;
+
+    let valid = tokio::task::spawn_blocking(move || {
+        auth::verify_password(&payload.password, &password_hash)
+    })
+    .await
+    .map_err(ApiError::internal)?;
+    if !valid {
+        return Err(unauthorized());
+    }
+
+    let token = app_state
+        .keys
+        .issue_token(user._id, user.name.clone())
+        .map_err(ApiError::internal)?;
+
+    Ok(Json(LoginResponse { token, user }))
+}
+
+/// Changes the caller's own profile. A new username is held to the same
+/// rules as at registration; guests have to claim their account first.
+#[utoipa::path(
+    patch,
+    path = "/user/{id}",
+    tag = "users",
+    params(("id" = Uuid, Path, description = "The caller's own account id")),
+    request_body = PatchUserJson,
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "The updated account and a new token", body = LoginResponse),
+        (status = 400, description = "Invalid change", body = ErrorBody),
+        (status = 401, description = "Missing or invalid token", body = ErrorBody),
+        (status = 403, description = "Not the caller's account", body = ErrorBody),
+        (status = 404, description = "No such account", body = ErrorBody),
+        (status = 409, description = "Username taken", body = ErrorBody),
+    )
+)]
+async fn patch_user(
+    State(app_state): State<Arc<AppState>>,
+    AuthUser(claims): AuthUser,
+    ApiPath(id): ApiPath<Uuid>,
+    ApiJson(payload): ApiJson<PatchUserJson>,
+) -> Result<Json<LoginResponse>, ApiError> {
+    if claims.sub != id {
+        return Err(ApiError::Forbidden(
+            "you can only change your own account".to_string(),
         ));
     }
 
-    conn = app_state
-        .as_ref()
-        .db
-        .db_pool
-        .get()
-        .await
-        .map_err(internal_error)?;
+    let mut update = ProfileUpdate::default();
+    if let Some(username) = payload.username {
+        validate_username(&username)?;
+        let user = app_state
+            .users
+            .find_by_id(id)
+            .await?
+            .ok_or_else(|| ApiError::NotFound("user not found".to_string()))?;
+        if user.is_guest {
+            return Err(ApiError::BadRequest(
+                "claim your guest account to choose a username".to_string(),
+            ));
+        }
+        update.username = Some(username);
+    }
+    if let Some(display_name) = payload.display_name {
+        let display_name = display_name.trim().to_string();
+        validate_display_name(&display_name)?;
+        update.display_name = Some((!display_name.is_empty()).then_some(display_name));
+    }
+    if let Some(avatar) = payload.avatar {
+        if !avatar.is_empty() {
+            validate_avatar(&avatar)?;
+        }
+        update.avatar = Some((!avatar.is_empty()).then_some(avatar));
+    }
 
-    let _ = conn
-        .execute(
-            "INSERT INTO users (id, username) VALUES ($1, $2)",
-            &[&uuid, &user_name.clone()],
-        )
-        .await
-        .map_err(internal_error)?;
+    let renamed = update.username.is_some();
+    let user = app_state
+        .users
+        .update_profile(id, update)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("user not found".to_string()))?;
 
-    conn = app_state
-        .as_ref()
-        .db
-        .db_pool
-        .get()
-        .await
-        .map_err(internal_error)?;
+    if renamed {
+        rename_connections(&app_state, &user).await;
+    }
 
-    let row = conn
-        .query_one(
-            "SELECT id, username, created_at, wins FROM users WHERE id = $1",
-            &[&uuid],
-        )
-        .await;
+    // The name is part of the token, so issue a fresh one
+    let token = app_state
+        .keys
+        .issue_token(user._id, user.name.clone())
+        .map_err(ApiError::internal)?;
 
-    match row {
-        Ok(row) => {
-            let doc = user_data::User {
-                _id: row.get(0),
-                name: row.get(1),
-                created_at: row.get(2),
-                wins: row.get(3),
-            };
+    Ok(Json(LoginResponse { token, user }))
+}
 
-            Ok(Json(doc))
-        }
-        Err(e) => Err(internal_error(e)),
+/// Puts a user's new name on the sockets they already have open.
+async fn rename_connections(app_state: &AppState, user: &User) {
+    let cmd = websocket_manager::Command::Rename {
+        user_id: user._id,
+        name: user.name.clone(),
+    };
+    let _ = app_state.wm_send.send(cmd).await;
+}
+
+/// Deletes the caller's own account. The row is anonymized rather than
+/// removed, and any sockets the user has open are closed.
+#[utoipa::path(
+    delete,
+    path = "/user/{id}",
+    tag = "users",
+    params(("id" = Uuid, Path, description = "The caller's own account id")),
+    security(("bearer" = [])),
+    responses(
+        (status = 204, description = "Account deleted"),
+        (status = 401, description = "Missing or invalid token", body = ErrorBody),
+        (status = 403, description = "Not the caller's account", body = ErrorBody),
+        (status = 404, description = "No such account", body = ErrorBody),
+    )
+)]
+async fn delete_user(
+    State(app_state): State<Arc<AppState>>,
+    AuthUser(claims): AuthUser,
+    ApiPath(id): ApiPath<Uuid>,
+) -> Result<StatusCode, ApiError> {
+    if claims.sub != id {
+        return Err(ApiError::Forbidden(
+            "you can only delete your own account".to_string(),
+        ));
     }
+
+    if !app_state.users.delete_user(id).await? {
+        return Err(ApiError::NotFound("user not found".to_string()));
+    }
+
+    let _ = app_state
+        .wm_send
+        .send(websocket_manager::Command::Disconnect {
+            user_id: id,
+            reason: "account deleted".to_string(),
+        })
+        .await;
+
+    Ok(StatusCode::NO_CONTENT)
 }
 
+/// The public profile of an account, looked up by username ignoring case.
+#[utoipa::path(
+    get,
+    path = "/user/{name}",
+    tag = "users",
+    params(("name" = String, Path, description = "Username")),
+    responses(
+        (status = 200, description = "The profile", body = PublicUser),
+        (status = 404, description = "No such account", body = ErrorBody),
+    )
+)]
 async fn get_user(
     State(app_state): State<Arc<AppState>>,
-    Path(name): Path<String>,
-) -> Result<Json<User>, (StatusCode, String)> {
-    let conn = app_state
-        .as_ref()
-        .db
-        .db_pool
-        .get()
-        .await
-        .map_err(internal_error)?;
-    let row = conn
-        .query_one(
-            "SELECT id, username, created_at, wins FROM users WHERE username = $1",
-            &[&name],
-        )
-        .await;
-    match row {
-        Ok(row) => {
-            let doc = user_data::User {
-                _id: row.get(0),
-                name: row.get(1),
-                created_at: row.get(2),
-                wins: row.get(3),
-            };
+    ApiPath(name): ApiPath<String>,
+) -> Result<Json<PublicUser>, ApiError> {
+    match app_state.users.find_by_name(&name).await? {
+        Some(user) => Ok(Json(user.into())),
+        None => Err(ApiError::NotFound("user not found".to_string())),
+    }
+}
 
-            Ok(Json(doc))
-        }
-        Err(e) => Err(internal_error(e)),
+/// The public profile of an account.
+#[utoipa::path(
+    get,
+    path = "/user/id/{id}",
+    tag = "users",
+    params(("id" = Uuid, Path, description = "Account id")),
+    responses(
+        (status = 200, description = "The profile", body = PublicUser),
+        (status = 404, description = "No such account", body = ErrorBody),
+    )
+)]
+async fn get_user_by_id(
+    State(app_state): State<Arc<AppState>>,
+    ApiPath(id): ApiPath<Uuid>,
+) -> Result<Json<PublicUser>, ApiError> {
+    match app_state.users.find_by_id(id).await? {
+        Some(user) => Ok(Json(user.into())),
+        None => Err(ApiError::NotFound("user not found".to_string())),
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let db_url: String = env::var("DATABASE_URL").unwrap();
+/// Resolves the ids the WebSocket user list carries into public profiles.
+/// Ids that match no account are left out of the result.
+#[utoipa::path(
+    post,
+    path = "/users/lookup",
+    tag = "users",
+    request_body = LookupJson,
+    responses(
+        (status = 200, description = "Profiles of the accounts found", body = Vec<PublicUser>),
+        (status = 400, description = "Too many ids", body = ErrorBody),
+    )
+)]
+async fn lookup_users(
+    State(app_state): State<Arc<AppState>>,
+    ApiJson(payload): ApiJson<LookupJson>,
+) -> Result<Json<Vec<PublicUser>>, ApiError> {
+    let mut ids = payload.ids;
+    if ids.len() > MAX_LOOKUP_IDS {
+        return Err(ApiError::BadRequest(format!(
+            "at most {} ids can be looked up at once",
+            MAX_LOOKUP_IDS
+        )));
+    }
+    ids.sort_unstable();
+    ids.dedup();
 
-    let pg_manager = PostgresConnectionManager::new_from_stringlike(db_url, NoTls).unwrap();
+    let users = app_state.users.find_by_ids(&ids).await?;
+    Ok(Json(users.into_iter().map(PublicUser::from).collect()))
+}
 
-    let pool = Pool::builder().build(pg_manager).await.unwrap();
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // Migrating needs only the database settings, so a migration job need
+    // not carry the rest of the server's configuration
+    let migrate_only = env::args().any(|arg| arg == "--migrate-only");
+    let loaded = if migrate_only {
+        Config::load_for_migrations()
+    } else {
+        Config::load()
+    };
+    let config = match loaded {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("invalid configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
 
+    // Only one of the two output layers is present
+    let log_format = config.server.log_format;
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "blackjack-backend=debug,tower_http=debug".into()),
+                .unwrap_or_else(|_| config.server.log_filter.as_str().into()),
         )
-        .with(tracing_subscriber::fmt::layer())
+        .with((log_format == LogFormat::Text).then(tracing_subscriber::fmt::layer))
+        .with((log_format == LogFormat::Json).then(|| tracing_subscriber::fmt::layer().json()))
         .init();
 
-    let _game = Blackjack::create_game();
+    tls::install_crypto_provider();
+
+    // Without a database everything is kept in memory, which is enough to
+    // develop and test against but loses all accounts on restart
+    let users: Arc<dyn UserRepository> = match &config.database.url {
+        Some(db_url) => {
+            let mut pg_config: tokio_postgres::Config = db_url.parse()?;
+            pg_config.ssl_mode(if config.database.tls {
+                SslMode::Require
+            } else {
+                SslMode::Disable
+            });
+            let connector = tls::postgres_connector(config.database.ca_file.as_deref())?;
+            let pg_manager = PostgresConnectionManager::new(pg_config, connector);
+            let pool = Pool::builder()
+                .max_size(config.database.pool_size)
+                .build(pg_manager)
+                .await?;
+
+            let applied = db::migrations::run(&pool).await?;
+            info!(?applied, "applied migrations");
+
+            Arc::new(PgUserRepository::new(pool))
+        }
+        None if migrate_only => return Err("DATABASE_URL must be set to migrate".into()),
+        None => {
+            warn!("DATABASE_URL is not set, keeping users in memory");
+            Arc::new(MemoryUserRepository::new())
+        }
+    };
+
+    if migrate_only {
+        return Ok(());
+    }
 
     // Create async task and access WebSocketManager with channels
-    let (wm_send, mut wm_read) = tokio::sync::mpsc::channel::<websocket_manager::Command>(10);
+    let (wm_send, mut wm_read) =
+        tokio::sync::mpsc::channel::<websocket_manager::Command>(config.server.channel_size);
 
     let _ = wm_send.clone();
 
-    let _ = tokio::spawn(async move {
+    tokio::spawn(async move {
         let mut websocket_manager = WebSocketManager::new();
 
-        // Start receiving messages
+        // Start receiving messages. Failures are reported and the loop keeps
+        // serving every other connection.
         while let Some(cmd) = wm_read.recv().await {
             use websocket_manager::Command::*;
 
-            match cmd {
-                AddWS { ws_send, resp, key } => {
-                    websocket_manager.add_ws(key.clone(), ws_send);
-                    let _ = resp.send(key.1);
-                    websocket_manager.update_all_list().await;
+            let result = match cmd {
+                AddWS {
+                    ws_send,
+                    cancel,
+                    resp,
+                    key,
+                    name,
+                } => {
+                    websocket_manager.add_ws(key, name, ws_send, cancel);
+                    let _ = resp.send(key.user_id);
+                    websocket_manager.update_all_list()
                 }
                 DeleteWS { key } => {
                     websocket_manager.remove_ws(key);
-                    websocket_manager.update_all_list().await;
+                    websocket_manager.update_all_list()
+                }
+                SendWS { key, msg } => websocket_manager.send_msg(key, msg),
+                UpdateUserList {} => websocket_manager.update_all_list(),
+                CreateRoom { table_id, host } => {
+                    websocket_manager.create_room(table_id, host);
+                    Ok(())
                 }
-                SendWS { key, msg } => {
-                    websocket_manager.send_msg(key, msg).await;
+                RemoveRoom { table_id } => websocket_manager.remove_room(table_id),
+                SetRoomHost { table_id, host } => {
+                    websocket_manager.set_room_host(table_id, host);
+                    Ok(())
                 }
-                UpdateUserList {} => {
-                    websocket_manager.update_all_list().await;
+                JoinRoom { table_id, key } => websocket_manager.join_room(table_id, key),
+                LeaveRoom { table_id, key } => websocket_manager.leave_room(table_id, key),
+                BroadcastTable { table_id, msg } => {
+                    websocket_manager.broadcast_table(table_id, msg)
+                }
+                Chat {
+                    key,
+                    scope,
+                    table_id,
+                    text,
+                } => websocket_manager.chat(key, scope, table_id, text),
+                Mute {
+                    table_id,
+                    key,
+                    target,
+                    muted,
+                } => websocket_manager.mute(table_id, key, target, muted),
+                Disconnect { user_id, reason } => {
+                    websocket_manager.disconnect_user(user_id, &reason)
+                }
+                Rename { user_id, name } => websocket_manager.rename_user(user_id, &name),
+                Kick { key, reason } => websocket_manager.kick(key, &reason),
+                ConnectedUsers { resp } => {
+                    let _ = resp.send(websocket_manager.connected_users());
+                    Ok(())
+                }
+                Ping { resp } => {
+                    let _ = resp.send(());
+                    Ok(())
+                }
+                CloseAll { reason, resp } => {
+                    websocket_manager.close_all(&reason);
+                    let _ = resp.send(());
+                    Ok(())
+                }
+            };
+
+            if let Err(e) = result {
+                error!(error = %e, "websocket manager command failed");
+            }
+        }
+    });
+
+    // Create async task that loads and saves player chips in order
+    let (ledger_send, mut ledger_read) = tokio::sync::mpsc::channel::<ledger::Command>(100);
+
+    let ledger_users = users.clone();
+
+    tokio::spawn(async move {
+        while let Some(cmd) = ledger_read.recv().await {
+            match cmd {
+                ledger::Command::LoadChips { id, resp } => {
+                    match ledger_users.load_chips(id).await {
+                        Ok(chips) => {
+                            let _ = resp.send(chips);
+                        }
+                        Err(e) => error!(user_id = %id, error = %e, "could not load chips"),
+                    }
+                }
+                ledger::Command::SaveBalance { balance } => {
+                    if let Err(e) = ledger_users.save_balance(&balance).await {
+                        error!(?balance, error = %e, "could not save balance");
+                    }
+                }
+                ledger::Command::Flush { resp } => {
+                    let _ = resp.send(());
                 }
             }
         }
     });
 
+    // Create async task that owns the game and reports table membership and
+    // table state to the WebSocketManager so it can route table messages
+    let (game_send, mut game_read) =
+        tokio::sync::mpsc::channel::<game::Command>(config.server.channel_size);
+
+    let game_wm_send = wm_send.clone();
+    let game_ledger_send = ledger_send.clone();
+    let rules = config.table;
+
+    tokio::spawn(async move {
+        let mut blackjack = Blackjack::create_game(rules);
+        // Answered once draining and no round is left in progress
+        let mut drained: Option<tokio::sync::oneshot::Sender<()>> = None;
+
+        while let Some(cmd) = game_read.recv().await {
+            use blackjack::game::Command::*;
+            use websocket_manager::Command as WM;
+
+            let changed = match cmd {
+                CreateTable {
+                    key,
+                    seat,
+                    chips,
+                    resp,
+                } => {
+                    let host = key.user_id;
+                    let result = blackjack.add_table(host, seat, chips);
+                    let changed = result.as_ref().ok().copied();
+                    if let Some(table_id) = changed {
+                        let _ = game_wm_send.send(WM::CreateRoom { table_id, host }).await;
+                        let _ = game_wm_send.send(WM::JoinRoom { table_id, key }).await;
+                    }
+                    let _ = resp.send(result);
+                    changed
+                }
+                JoinTable {
+                    table_id,
+                    key,
+                    seat,
+                    chips,
+                    resp,
+                } => {
+                    let result = blackjack.join_table(table_id, key.user_id, seat, chips);
+                    let changed = result.is_ok().then_some(table_id);
+                    if result.is_ok() {
+                        let _ = game_wm_send.send(WM::JoinRoom { table_id, key }).await;
+                    }
+                    // The seat taken is in the table state everyone receives
+                    let _ = resp.send(result.map(|_| ()));
+                    changed
+                }
+                WatchTable {
+                    table_id,
+                    key,
+                    resp,
+                } => {
+                    let result = blackjack.watch_table(table_id, key.user_id);
+                    let changed = result.is_ok().then_some(table_id);
+                    if result.is_ok() {
+                        let _ = game_wm_send.send(WM::JoinRoom { table_id, key }).await;
+                    }
+                    let _ = resp.send(result);
+                    changed
+                }
+                LeaveTable {
+                    table_id,
+                    key,
+                    resp,
+                } => {
+                    let result = blackjack.leave_table(table_id, key.user_id);
+                    let changed = result.is_ok().then_some(table_id);
+                    if let Ok(departure) = &result {
+                        let _ = game_wm_send.send(WM::LeaveRoom { table_id, key }).await;
+                        let cmd = match *departure {
+                            Departure::Left => None,
+                            Departure::HostChanged(host) => {
+                                Some(WM::SetRoomHost { table_id, host })
+                            }
+                            Departure::TableClosed => Some(WM::RemoveRoom { table_id }),
+                        };
+                        if let Some(cmd) = cmd {
+                            let _ = game_wm_send.send(cmd).await;
+                        }
+                    }
+                    // Save the leaver's chips before they can sit down again
+                    for balance in blackjack.take_balances() {
+                        let cmd = ledger::Command::SaveBalance { balance };
+                        let _ = game_ledger_send.send(cmd).await;
+                    }
+                    let _ = resp.send(result.map(|_| ()));
+                    changed
+                }
+                Act {
+                    table_id,
+                    key,
+                    action,
+                    resp,
+                } => {
+                    let result = blackjack.act(table_id, key.user_id, action);
+                    let changed = result.is_ok().then_some(table_id);
+                    let _ = resp.send(result);
+                    changed
+                }
+                Ping { resp } => {
+                    let _ = resp.send(());
+                    None
+                }
+                Drain { resp } => {
+                    blackjack.stop_accepting();
+                    drained = Some(resp);
+                    None
+                }
+                CloseAll { resp } => {
+                    for table_id in blackjack.close_all() {
+                        let _ = game_wm_send.send(WM::RemoveRoom { table_id }).await;
+                    }
+                    // Refunds are queued for saving before the caller hears back
+                    for balance in blackjack.take_balances() {
+                        let cmd = ledger::Command::SaveBalance { balance };
+                        let _ = game_ledger_send.send(cmd).await;
+                    }
+                    let _ = resp.send(());
+                    None
+                }
+            };
+
+            for balance in blackjack.take_balances() {
+                let cmd = ledger::Command::SaveBalance { balance };
+                let _ = game_ledger_send.send(cmd).await;
+            }
+
+            let gauges = metrics::get();
+            gauges.tables_active.set(blackjack.table_count() as i64);
+            gauges.players_seated.set(blackjack.player_count() as i64);
+
+            if !blackjack.round_in_progress() {
+                if let Some(resp) = drained.take() {
+                    let _ = resp.send(());
+                }
+            }
+
+            // Everyone in the room, players and spectators alike, gets the
+            // redacted state after each change
+            let Some(table_id) = changed else {
+                continue;
+            };
+            if let Some(view) = blackjack.view(table_id) {
+                let msg = SendWS {
+                    msg_data_table: Some(view),
+                    ..SendWS::new(websocket_manager::MsgType::TableState)
+                };
+                let _ = game_wm_send
+                    .send(WM::BroadcastTable { table_id, msg })
+                    .await;
+            }
+        }
+    });
+
+    let session_ttl = i64::try_from(config.timers.session_ttl_secs)
+        .ok()
+        .and_then(chrono::Duration::try_seconds)
+        .ok_or("timers.session_ttl_secs is out of range")?;
+
+    let bind = config.server.bind;
+    let tls_files = config
+        .server
+        .tls_cert
+        .clone()
+        .zip(config.server.tls_key.clone());
+    let shutdown_grace = Duration::from_secs(config.timers.shutdown_grace_secs);
+
+    // A guest has no password, so once the token issued with the account
+    // expires nobody can sign in as it again. Those still connected are
+    // left until a later sweep.
+    let sweep_users = users.clone();
+    let sweep_wm_send = wm_send.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(GUEST_SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            let (resp, connected) = tokio::sync::oneshot::channel();
+            let command = websocket_manager::Command::ConnectedUsers { resp };
+            if sweep_wm_send.send(command).await.is_err() {
+                break;
+            }
+            let Ok(connected) = connected.await else {
+                break;
+            };
+            let created_before = chrono::Utc::now() - session_ttl;
+            match sweep_users
+                .delete_expired_guests(created_before, &connected)
+                .await
+            {
+                Ok(0) => {}
+                Ok(removed) => info!(removed, "removed expired guest accounts"),
+                Err(e) => error!(error = %e, "could not remove expired guest accounts"),
+            }
+        }
+    });
+
+    let keys = auth::Keys::new(config.server.session_secret.as_bytes(), session_ttl);
+    let chat_filter = ChatFilter::new(&config.chat.blocked_words);
+
     let shared_db_state = Arc::new(AppState {
-        db: DB { db_pool: pool },
+        config,
+        users,
+        keys,
         wm_send,
+        game_send,
+        ledger_send,
+        chat_filter,
+        card_cache: card::PngCache::new(),
+        draining: AtomicBool::new(false),
+        writers: TaskTracker::new(),
     });
 
+    let drain_state = shared_db_state.clone();
+
+    let limits = &shared_db_state.config.rate_limit;
+    let request_limiter = Arc::new(IpRateLimiter::new(limits.http_burst, limits.http_per_sec));
+    let account_limiter = Arc::new(IpRateLimiter::new(
+        limits.account_burst,
+        limits.account_per_sec,
+    ));
+    let limiters = [request_limiter.clone(), account_limiter.clone()];
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(rate_limit::PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+            for limiter in limiters.iter() {
+                limiter.prune();
+            }
+        }
+    });
+
+    let app = router(shared_db_state, request_limiter, account_limiter);
+
+    let make_service = app.into_make_service_with_connect_info::<SocketAddr>();
+    match tls_files {
+        Some((cert, key)) => {
+            let tls_config = RustlsConfig::from_pem_file(cert, key).await?;
+            let handle = axum_server::Handle::new();
+            let shutdown = handle.clone();
+            tokio::spawn(async move {
+                drain(drain_state, shutdown_grace).await;
+                shutdown.graceful_shutdown(None);
+            });
+            info!(%bind, "listening with TLS");
+            axum_server::bind_rustls(bind, tls_config)
+                .handle(handle)
+                .serve(make_service)
+                .await?;
+        }
+        None => {
+            let listener = tokio::net::TcpListener::bind(bind).await?;
+            info!(%bind, "listening");
+            axum::serve(listener, make_service)
+                .with_graceful_shutdown(drain(drain_state, shutdown_grace))
+                .await?;
+        }
+    }
+    info!("shut down");
+    Ok(())
+}
+
+/// Every route with its middleware. Account creation is limited by
+/// `account_limiter` and the rest of the API by `request_limiter`.
+fn router(
+    app_state: Arc<AppState>,
+    request_limiter: Arc<IpRateLimiter>,
+    account_limiter: Arc<IpRateLimiter>,
+) -> Router {
+    // No configured origins means the API is open to any site
+    let allow_origin = if app_state.config.cors.origins.is_empty() {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(
+            app_state
+                .config
+                .cors
+                .origins
+                .iter()
+                // Each origin was checked to be a valid header value on load
+                .map(|origin| origin.parse().unwrap()),
+        )
+    };
+
     let cors = CorsLayer::new()
-        // allow `GET` and `POST` when accessing the resource
-        .allow_methods(vec![Method::GET, Method::POST, Method::CONNECT])
-        // allow requests from any origin
-        .allow_origin(Any)
-        // allow any headers
-        .allow_headers([CONTENT_TYPE]);
-
-    let app = Router::new()
+        // allow the methods the routes below use
+        .allow_methods(vec![
+            Method::GET,
+            Method::POST,
+            Method::PATCH,
+            Method::DELETE,
+            Method::CONNECT,
+        ])
+        // allow requests from the configured origins
+        .allow_origin(allow_origin)
+        // allow JSON bodies and bearer tokens
+        .allow_headers([CONTENT_TYPE, AUTHORIZATION])
+        // only with a list of origins, which `Config::load` ensures
+        .allow_credentials(app_state.config.cors.allow_credentials);
+
+    // A route layer only wraps the routes added before it, so account
+    // creation gets both limits and the probes below get none
+    Router::new()
+        .route("/user/create", post(add_user))
+        .route("/user/guest", post(add_guest))
+        .route_layer(middleware::from_fn_with_state(
+            account_limiter,
+            rate_limit::limit_by_ip,
+        ))
         .route("/", get(|| async { "Hello, World!" }))
         .route("/card/:value/:suit", get(get_card))
-        .route("/user/create", post(add_user))
-        .route("/user/:name", get(get_user))
-        .route("/ws/:id", get(ws_handler))
-        .with_state(shared_db_state)
+        .route("/user/login", post(login))
+        .route("/user/claim", post(claim_guest))
+        .route("/user/id/:id", get(get_user_by_id))
+        .route("/users/lookup", post(lookup_users))
+        // PATCH and DELETE take an account id in this segment; the router
+        // allows one parameter name per path, so the spec names it there.
+        .route(
+            "/user/:name",
+            get(get_user).patch(patch_user).delete(delete_user),
+        )
+        .route("/ws", get(ws_handler))
+        .route_layer(middleware::from_fn_with_state(
+            request_limiter,
+            rate_limit::limit_by_ip,
+        ))
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
+        .route("/metrics", get(metrics::metrics_handler))
+        .route("/openapi.json", get(api_doc::openapi))
+        .route("/ws/schema.json", get(api_doc::ws_schema))
+        .with_state(app_state)
         .layer(cors)
         .layer(
-            TraceLayer::new_for_http()
-                .make_span_with(DefaultMakeSpan::default().include_headers(true)),
-        );
+            TraceLayer::new_for_http().make_span_with(|request: &Request<Body>| {
+                // Only the path is logged: the query of /ws may carry a token
+                debug_span!(
+                    "request",
+                    method = %request.method(),
+                    uri = %request.uri().path(),
+                    version = ?request.version(),
+                    headers = ?request.headers(),
+                )
+            }),
+        )
+        // Outside the trace layer, so tokens show up as `Sensitive`
+        .layer(SetSensitiveRequestHeadersLayer::new([
+            AUTHORIZATION,
+            COOKIE,
+        ]))
+}
 
-    // run our app with hyper, listening globally on port 3000
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<SocketAddr>(),
-    )
-    .await
-    .unwrap();
-    Ok(())
+/// Resolves on Ctrl+C or, on Unix, SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// Winds the server down once a shutdown signal arrives. Nobody can sit down
+/// or start a round, rounds under way get `grace` to finish, then every table
+/// is closed with unsettled bets refunded, the chips are saved and sockets
+/// are closed with a reason. The server stops when this returns, once the
+/// close frames are sent or the write timeout has passed.
+async fn drain(app_state: Arc<AppState>, grace: Duration) {
+    shutdown_signal().await;
+    app_state.draining.store(true, Ordering::Relaxed);
+    info!(?grace, "shutting down, giving rounds time to finish");
+
+    let (resp, drained) = tokio::sync::oneshot::channel();
+    let _ = app_state
+        .game_send
+        .send(game::Command::Drain { resp })
+        .await;
+    if tokio::time::timeout(grace, drained).await.is_err() {
+        warn!("rounds still in progress, refunding their bets");
+    }
+
+    let (resp, closed) = tokio::sync::oneshot::channel();
+    let _ = app_state
+        .game_send
+        .send(game::Command::CloseAll { resp })
+        .await;
+    let _ = closed.await;
+
+    // The ledger works in order, so once it answers every save queued by
+    // the game has been written
+    let (resp, flushed) = tokio::sync::oneshot::channel();
+    let _ = app_state
+        .ledger_send
+        .send(ledger::Command::Flush { resp })
+        .await;
+    let _ = flushed.await;
+
+    let (resp, disconnected) = tokio::sync::oneshot::channel();
+    let cmd = websocket_manager::Command::CloseAll {
+        reason: "server is shutting down".to_string(),
+        resp,
+    };
+    let _ = app_state.wm_send.send(cmd).await;
+    let _ = disconnected.await;
+
+    // The frames are only queued so far, and the writers would be dropped
+    // along with the runtime before sending them
+    let write_timeout = Duration::from_secs(app_state.config.timers.write_timeout_secs);
+    app_state.writers.close();
+    if tokio::time::timeout(write_timeout, app_state.writers.wait())
+        .await
+        .is_err()
+    {
+        warn!(
+            remaining = app_state.writers.len(),
+            "sockets still writing, closing anyway"
+        );
+    }
 }
 
+/// Upgrades to the game protocol described at `/ws/schema.json`.
+#[utoipa::path(
+    get,
+    path = "/ws",
+    tag = "websocket",
+    params(WsQuery),
+    security(("bearer" = [])),
+    responses(
+        (status = 101, description = "Switched to WebSocket"),
+        (status = 400, description = "Not a WebSocket upgrade", body = ErrorBody),
+        (status = 401, description = "Missing or invalid token", body = ErrorBody),
+        (status = 403, description = "Origin not allowed", body = ErrorBody),
+    )
+)]
 async fn ws_handler(
     State(app_state): State<Arc<AppState>>,
-    ws: WebSocketUpgrade,
+    ws: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
     user_agent: Option<TypedHeader<headers::UserAgent>>,
-    Path(id): Path<String>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    ApiQuery(query): ApiQuery<WsQuery>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
-) -> Result<Response, (StatusCode, String)> {
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let ws = ws.map_err(|rejection| ApiError::BadRequest(rejection.body_text()))?;
+
+    // CORS does not cover WebSockets: a browser will open one from any
+    // page, sending along that page's origin. Only browsers send an Origin,
+    // so other clients are let through.
+    if let Some(origin) = headers.get(ORIGIN) {
+        let allowed = origin
+            .to_str()
+            .is_ok_and(|x| app_state.config.cors.allows(x));
+        if !allowed {
+            warn!(%addr, ?origin, "websocket upgrade from a disallowed origin");
+            return Err(ApiError::Forbidden("origin not allowed".to_string()));
+        }
+    }
+
     let user_agent = match user_agent {
         Some(user_data) => user_data.to_string(),
         None => String::from("Unknown browser"),
     };
-    println!("{} at {}", user_agent, addr);
+    debug!(%addr, user_agent, "websocket upgrade requested");
 
     let arc = &app_state.clone();
 
-    let conn = arc
-        .as_ref()
-        .db
-        .db_pool
-        .get()
-        .await
-        .map_err(internal_error)?;
-
-    let uuid = Uuid::parse_str(id.as_str()).map_err(internal_error)?;
+    // Browsers cannot set headers on a WebSocket upgrade, so the token may
+    // also come in the query string
+    let token = match (&bearer, &query.token) {
+        (Some(TypedHeader(Authorization(bearer))), _) => bearer.token(),
+        (None, Some(token)) => token.as_str(),
+        (None, None) => {
+            return Err(ApiError::Unauthorized("missing session token".to_string()));
+        }
+    };
+    let invalid_token = || ApiError::Unauthorized("invalid session token".to_string());
+    let claims = arc.keys.verify_token(token).map_err(|_| invalid_token())?;
 
-    // Verify that id passed is valid
-    let row = conn
-        .query_one(
-            "SELECT username, id FROM users WHERE id = $1 ",
-            &[&uuid.clone()],
-        )
-        .await
-        .map_err(internal_error)?;
+    // Verify that the account behind the token still exists
+    let user = arc
+        .users
+        .find_by_id(claims.sub)
+        .await?
+        .ok_or_else(invalid_token)?;
 
     Ok(ws
-        .on_failed_upgrade(|error| {
-            println!("Error: {}", error);
+        .on_failed_upgrade(move |error| {
+            warn!(%addr, error = %error, "websocket upgrade failed");
         })
-        .on_upgrade(move |socket| handle_socket(app_state, socket, addr, (row.get(0), row.get(1)))))
+        .on_upgrade(move |socket| handle_socket(app_state, socket, addr, user.name, user._id)))
 }
 
 async fn handle_socket(
     app_state: Arc<AppState>,
     socket: WebSocket,
     who: SocketAddr,
-    key: (String, Uuid),
+    name: String,
+    user_id: Uuid,
 ) {
     let (sink, mut stream) = socket.split();
 
-    let wm_send_copy = app_state.wm_send.clone();
+    // A user may be connected more than once, so each socket has its own key
+    let key = ConnKey {
+        id: Uuid::new_v4(),
+        user_id,
+    };
+
+    // Everything logged on behalf of this connection carries who it is
+    let span =
+        info_span!("connection", conn_id = %key.id, user_id = %user_id, user = %name, addr = %who);
+    span.in_scope(|| info!("connected"));
+
+    // The manager only enqueues; this connection's own task does the writing.
+    let write_timeout = Duration::from_secs(app_state.config.timers.write_timeout_secs);
+    let ws_send =
+        span.in_scope(|| websocket_manager::spawn_writer(sink, write_timeout, &app_state.writers));
 
-    let mut key_clone = key.clone();
+    // Cancelled by the manager when it drops the connection, so the reader
+    // below stops too and the user leaves their table
+    let cancel = CancellationToken::new();
+
+    let wm_send_copy = app_state.wm_send.clone();
+    let reader_cancel = cancel.clone();
 
     let _ = tokio::spawn(async move {
         let (resp_send, resp_recv) = tokio::sync::oneshot::channel();
         let cmd = websocket_manager::Command::AddWS {
-            ws_send: sink,
+            ws_send,
+            cancel: reader_cancel,
             resp: resp_send,
-            key: key_clone,
+            key,
+            name,
         };
 
         let _ = wm_send_copy.send(cmd).await;
@@ -335,18 +1286,12 @@ async fn handle_socket(
     })
     .await;
 
-    key_clone = key.clone();
-
     let wm_send_copy2 = app_state.wm_send.clone();
 
     let _ = tokio::spawn(async move {
-        let key = key_clone;
-
-        let send_ws = websocket_manager::SendWS {
-            msg_type: websocket_manager::MsgType::SelfUuid,
-            msg_data_str: Some(key.1.to_string()),
-            msg_data_arr: None,
-            msg_data_keys: None,
+        let send_ws = SendWS {
+            msg_data_str: Some(key.user_id.to_string()),
+            ..SendWS::new(websocket_manager::MsgType::SelfUuid)
         };
 
         let cmd = websocket_manager::Command::SendWS { key, msg: send_ws };
@@ -359,68 +1304,428 @@ async fn handle_socket(
     })
     .await;
 
-    let wm_send_copy3 = app_state.wm_send.clone();
+    tokio::spawn(
+        async move {
+            let mut session = Session {
+                key,
+                table: None,
+                chat: ChatLimiter::new(&app_state.config.chat),
+                limiter: MessageLimiter::new(&app_state.config.rate_limit),
+            };
+            loop {
+                let msg = tokio::select! {
+                    msg = stream.next() => msg,
+                    _ = cancel.cancelled() => break,
+                };
+                let Some(Ok(msg)) = msg else {
+                    break;
+                };
+                if process_message(msg, &mut session, &app_state)
+                    .await
+                    .is_break()
+                {
+                    break;
+                }
+            }
 
-    let _ = tokio::spawn(async move {
-        let wm_send = wm_send_copy3;
-        while let Some(Ok(msg)) = stream.next().await {
-            if process_message(msg, who, key.clone(), &wm_send)
-                .await
-                .is_break()
-            {
-                break;
+            // However the socket ended, give up the seat and the connection
+            if let Some(table_id) = session.table.take() {
+                leave_table(&app_state, session.key, table_id).await;
             }
+            let cmd = websocket_manager::Command::DeleteWS { key: session.key };
+            let _ = app_state.wm_send.send(cmd).await;
+            info!("disconnected");
         }
-    });
+        .instrument(span),
+    );
+}
 
-    println!("Websocket context {who} completed handle_socket");
+/// State owned by a connection's reader task.
+struct Session {
+    key: ConnKey,
+    table: Option<Uuid>,
+    chat: ChatLimiter,
+    limiter: MessageLimiter,
 }
 
 async fn process_message(
     msg: Message,
-    who: SocketAddr,
-    key: (String, Uuid),
-    recv: &tokio::sync::mpsc::Sender<websocket_manager::Command>,
+    session: &mut Session,
+    app_state: &AppState,
 ) -> ControlFlow<(), ()> {
+    metrics::get().ws_messages_received.inc();
+    if !matches!(msg, Message::Close(_)) {
+        match session.limiter.check() {
+            Verdict::Allow => {}
+            Verdict::Reject => {
+                let msg = SendWS::error("too many messages, slow down");
+                send_self(app_state, session, msg).await;
+                return ControlFlow::Continue(());
+            }
+            Verdict::Disconnect => {
+                warn!("disconnecting for sending too many messages");
+                let cmd = websocket_manager::Command::Kick {
+                    key: session.key,
+                    reason: "too many messages".to_string(),
+                };
+                let _ = app_state.wm_send.send(cmd).await;
+                return ControlFlow::Break(());
+            }
+        }
+    }
     match msg {
         Message::Text(t) => {
-            println!(">>> {who} sent str: {t:?}");
+            trace!(text = %t, "received text");
+            match serde_json::from_str::<RecvWS>(&t) {
+                Ok(recv) => process_recv(recv, session, app_state).await,
+                Err(e) => send_self(app_state, session, SendWS::error(e)).await,
+            }
         }
         Message::Binary(d) => {
-            println!(">>> {} sent {} bytes: {:?}", who, d.len(), d);
+            debug!(bytes = d.len(), "received binary, ignoring");
         }
         Message::Close(c) => {
-            if let Some(cf) = c {
-                println!(
-                    ">>> {} sent close with code {} and reason `{}`",
-                    who, cf.code, cf.reason
-                );
-            } else {
-                println!(">>> {who} somehow sent close message without CloseFrame");
+            match c {
+                Some(cf) => debug!(code = cf.code, reason = %cf.reason, "received close"),
+                None => debug!("received close without a frame"),
             }
-            let cmd = websocket_manager::Command::DeleteWS { key };
-            let _ = recv.send(cmd).await;
             return ControlFlow::Break(());
         }
 
         Message::Pong(v) => {
-            println!(">>> {who} sent pong with {v:?}");
+            trace!(?v, "received pong");
         }
         // You should never need to manually handle Message::Ping, as axum's websocket library
         // will do so for you automagically by replying with Pong and copying the v according to
         // spec. But if you need the contents of the pings you can see them here.
         Message::Ping(v) => {
-            println!(">>> {who} sent ping with {v:?}");
+            trace!(?v, "received ping");
         }
     }
     ControlFlow::Continue(())
 }
 
-/// Utility function for mapping any error into a `500 Internal Server Error`
-/// response.
-fn internal_error<E>(err: E) -> (StatusCode, String)
-where
-    E: std::error::Error,
-{
-    (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+async fn process_recv(recv: RecvWS, session: &mut Session, app_state: &AppState) {
+    use websocket_manager::Command as WM;
+
+    match recv {
+        RecvWS::CreateTable { seat } => {
+            if let Some(table_id) = session.table.take() {
+                leave_table(app_state, session.key, table_id).await;
+            }
+            let Some(chips) = load_chips(app_state, session.key.user_id).await else {
+                let msg = SendWS::error("could not load chips");
+                return send_self(app_state, session, msg).await;
+            };
+            let (resp, resp_recv) = tokio::sync::oneshot::channel();
+            let cmd = game::Command::CreateTable {
+                key: session.key,
+                seat,
+                chips,
+                resp,
+            };
+            let _ = app_state.game_send.send(cmd).await;
+            match resp_recv.await {
+                Ok(Ok(table_id)) => session.table = Some(table_id),
+                Ok(Err(e)) => send_self(app_state, session, SendWS::error(e)).await,
+                Err(_) => {}
+            }
+        }
+        RecvWS::JoinTable { table_id, .. } | RecvWS::WatchTable { table_id } => {
+            // Staying at the same table, e.g. a spectator taking a seat,
+            // keeps the room
+            if let Some(old) = session.table.filter(|old| *old != table_id) {
+                session.table = None;
+                leave_table(app_state, session.key, old).await;
+            }
+            let (resp, resp_recv) = tokio::sync::oneshot::channel();
+            let key = session.key;
+            let cmd = match recv {
+                RecvWS::JoinTable { seat, .. } => {
+                    let Some(chips) = load_chips(app_state, session.key.user_id).await else {
+                        let msg = SendWS::error("could not load chips");
+                        return send_self(app_state, session, msg).await;
+                    };
+                    game::Command::JoinTable {
+                        table_id,
+                        key,
+                        seat,
+                        chips,
+                        resp,
+                    }
+                }
+                _ => game::Command::WatchTable {
+                    table_id,
+                    key,
+                    resp,
+                },
+            };
+            let _ = app_state.game_send.send(cmd).await;
+            match resp_recv.await {
+                Ok(Ok(())) => session.table = Some(table_id),
+                Ok(Err(e)) => send_self(app_state, session, SendWS::error(e)).await,
+                Err(_) => {}
+            }
+        }
+        RecvWS::LeaveTable => {
+            if let Some(table_id) = session.table.take() {
+                leave_table(app_state, session.key, table_id).await;
+            }
+        }
+        RecvWS::Bet { .. } | RecvWS::Deal | RecvWS::Hit | RecvWS::Stand | RecvWS::Split => {
+            let action = match recv {
+                RecvWS::Bet { spots } => Action::Bet { spots },
+                RecvWS::Deal => Action::Deal,
+                RecvWS::Hit => Action::Hit,
+                RecvWS::Split => Action::Split,
+                _ => Action::Stand,
+            };
+            let Some(table_id) = session.table else {
+                let msg = SendWS::error(game::GameError::NotSeated);
+                return send_self(app_state, session, msg).await;
+            };
+            let (resp, resp_recv) = tokio::sync::oneshot::channel();
+            let cmd = game::Command::Act {
+                table_id,
+                key: session.key,
+                action,
+                resp,
+            };
+            let _ = app_state.game_send.send(cmd).await;
+            if let Ok(Err(e)) = resp_recv.await {
+                send_self(app_state, session, SendWS::error(e)).await;
+            }
+        }
+        RecvWS::Chat { scope, text } => {
+            let table_id = match scope {
+                ChatScope::Lobby => None,
+                ChatScope::Table => session.table,
+            };
+            match session.chat.check(&app_state.chat_filter, &text) {
+                Ok(text) => {
+                    let cmd = WM::Chat {
+                        key: session.key,
+                        scope,
+                        table_id,
+                        text,
+                    };
+                    let _ = app_state.wm_send.send(cmd).await;
+                }
+                Err(e) => send_self(app_state, session, SendWS::error(e)).await,
+            }
+        }
+        RecvWS::Mute { user_id } | RecvWS::Unmute { user_id } => {
+            let muted = matches!(recv, RecvWS::Mute { .. });
+            let Some(table_id) = session.table else {
+                let msg = SendWS::error(chat::ChatError::NotAtTable);
+                return send_self(app_state, session, msg).await;
+            };
+            let cmd = WM::Mute {
+                table_id,
+                key: session.key,
+                target: user_id,
+                muted,
+            };
+            let _ = app_state.wm_send.send(cmd).await;
+        }
+    }
+}
+
+async fn leave_table(app_state: &AppState, key: ConnKey, table_id: Uuid) {
+    let (resp, resp_recv) = tokio::sync::oneshot::channel();
+    let cmd = game::Command::LeaveTable {
+        table_id,
+        key,
+        resp,
+    };
+    let _ = app_state.game_send.send(cmd).await;
+    let _ = resp_recv.await;
+}
+
+async fn load_chips(app_state: &AppState, id: Uuid) -> Option<u32> {
+    let (resp, resp_recv) = tokio::sync::oneshot::channel();
+    let cmd = ledger::Command::LoadChips { id, resp };
+    let _ = app_state.ledger_send.send(cmd).await;
+    resp_recv.await.ok().flatten()
+}
+
+async fn send_self(app_state: &AppState, session: &Session, msg: SendWS) {
+    let cmd = websocket_manager::Command::SendWS {
+        key: session.key,
+        msg,
+    };
+    let _ = app_state.wm_send.send(cmd).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::extract::connect_info::MockConnectInfo;
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    /// The whole API over an in-memory repository. The tasks behind the
+    /// channels are not running, which the account routes do not need.
+    fn app() -> Router {
+        let mut config = Config::default();
+        config.server.session_secret = "a test secret of at least 32 bytes".to_string();
+        let keys = auth::Keys::new(config.server.session_secret.as_bytes(), auth::TOKEN_TTL);
+        let limits = &config.rate_limit;
+        let request_limiter = Arc::new(IpRateLimiter::new(limits.http_burst, limits.http_per_sec));
+        let account_limiter = Arc::new(IpRateLimiter::new(
+            limits.account_burst,
+            limits.account_per_sec,
+        ));
+
+        let app_state = Arc::new(AppState {
+            config,
+            users: Arc::new(MemoryUserRepository::new()),
+            keys,
+            wm_send: tokio::sync::mpsc::channel(1).0,
+            game_send: tokio::sync::mpsc::channel(1).0,
+            ledger_send: tokio::sync::mpsc::channel(1).0,
+            chat_filter: ChatFilter::default(),
+            card_cache: card::PngCache::new(),
+            draining: AtomicBool::new(false),
+            writers: TaskTracker::new(),
+        });
+        router(app_state, request_limiter, account_limiter)
+            .layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 4000))))
+    }
+
+    async fn call(
+        app: &Router,
+        method: Method,
+        uri: &str,
+        token: Option<&str>,
+        body: Option<Value>,
+    ) -> (StatusCode, Value) {
+        let mut request = Request::builder().method(method).uri(uri);
+        if let Some(token) = token {
+            request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let request = match body {
+            Some(body) => request
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string())),
+            None => request.body(Body::empty()),
+        }
+        .unwrap();
+
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = serde_json::from_slice(&bytes).unwrap_or(Value::Null);
+        (status, body)
+    }
+
+    async fn register(app: &Router, name: &str) -> (StatusCode, Value) {
+        let body = json!({"name": name, "password": "correct horse"});
+        call(app, Method::POST, "/user/create", None, Some(body)).await
+    }
+
+    async fn login(app: &Router, name: &str, password: &str) -> (StatusCode, Value) {
+        let body = json!({"name": name, "password": password});
+        call(app, Method::POST, "/user/login", None, Some(body)).await
+    }
+
+    #[tokio::test]
+    async fn register_then_login() {
+        let app = app();
+        let (status, user) = register(&app, "alice").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(user["name"], "alice");
+        assert_eq!(user["is_guest"], false);
+
+        // Usernames are looked up ignoring case
+        let (status, body) = login(&app, "ALICE", "correct horse").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["user"]["_id"], user["_id"]);
+        assert!(body["token"].as_str().is_some_and(|x| !x.is_empty()));
+
+        // Anyone can see the profile, but not the chips
+        let (status, profile) = call(&app, Method::GET, "/user/Alice", None, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(profile["_id"], user["_id"]);
+        assert_eq!(profile.get("chips"), None);
+
+        let (status, body) = login(&app, "alice", "wrong horse").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["code"], "unauthorized");
+        let (status, _) = login(&app, "nobody", "correct horse").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn duplicate_names_conflict() {
+        let app = app();
+        assert_eq!(register(&app, "alice").await.0, StatusCode::OK);
+
+        let (status, body) = register(&app, "Alice").await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["code"], "conflict");
+
+        let (status, body) = register(&app, "admin").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "bad_request");
+    }
+
+    #[tokio::test]
+    async fn guests_can_claim_their_account() {
+        let app = app();
+        let (status, guest) = call(&app, Method::POST, "/user/guest", None, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(guest["user"]["is_guest"], true);
+        let token = guest["token"].as_str().unwrap();
+
+        let claim = json!({"name": "bob", "password": "correct horse"});
+        let (status, _) = call(&app, Method::POST, "/user/claim", None, Some(claim.clone())).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, claimed) =
+            call(&app, Method::POST, "/user/claim", Some(token), Some(claim)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(claimed["user"]["_id"], guest["user"]["_id"]);
+        assert_eq!(claimed["user"]["name"], "bob");
+        assert_eq!(claimed["user"]["is_guest"], false);
+
+        // Only guests can be claimed, and once is enough
+        let token = claimed["token"].as_str().unwrap();
+        let claim = json!({"name": "robert", "password": "correct horse"});
+        let (status, _) = call(&app, Method::POST, "/user/claim", Some(token), Some(claim)).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        assert_eq!(login(&app, "bob", "correct horse").await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn users_delete_only_their_own_account() {
+        let app = app();
+        let (_, alice) = register(&app, "alice").await;
+        let (_, carol) = register(&app, "carol").await;
+        let (_, body) = login(&app, "alice", "correct horse").await;
+        let token = body["token"].as_str().unwrap();
+
+        let uri = format!("/user/{}", carol["_id"].as_str().unwrap());
+        let (status, _) = call(&app, Method::DELETE, &uri, Some(token), None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = call(&app, Method::DELETE, &uri, None, None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let uri = format!("/user/{}", alice["_id"].as_str().unwrap());
+        let (status, _) = call(&app, Method::DELETE, &uri, Some(token), None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = call(&app, Method::DELETE, &uri, Some(token), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, _) = call(&app, Method::GET, "/user/alice", None, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = login(&app, "alice", "correct horse").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        // The name is free again
+        assert_eq!(register(&app, "alice").await.0, StatusCode::OK);
+    }
 }
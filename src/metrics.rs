@@ -0,0 +1,166 @@
+use std::sync::{Arc, LazyLock};
+
+use axum::{extract::State, http::header, response::IntoResponse};
+use prometheus::{
+    Encoder, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use tokio::sync::mpsc;
+
+use crate::{blackjack::game::Outcome, AppState};
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// Everything exported on `/metrics`. Counters are bumped where things
+/// happen; gauges that mirror state held elsewhere are set at scrape time.
+pub struct Metrics {
+    registry: Registry,
+    pub ws_connections: IntGauge,
+    pub ws_messages_received: IntCounter,
+    pub ws_messages_sent: IntCounter,
+    pub tables_active: IntGauge,
+    pub players_seated: IntGauge,
+    pub rounds_completed: IntCounter,
+    hands: IntCounterVec,
+    pub chips_wagered: IntCounter,
+    pub chips_paid: IntCounter,
+    db_pool_connections: IntGauge,
+    db_pool_idle: IntGauge,
+    queue_depth: IntGaugeVec,
+}
+
+pub fn get() -> &'static Metrics {
+    &METRICS
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+        let metrics = Metrics {
+            ws_connections: IntGauge::new("blackjack_ws_connections", "Open WebSocket connections")
+                .unwrap(),
+            ws_messages_received: IntCounter::new(
+                "blackjack_ws_messages_received_total",
+                "WebSocket messages received from clients",
+            )
+            .unwrap(),
+            ws_messages_sent: IntCounter::new(
+                "blackjack_ws_messages_sent_total",
+                "WebSocket messages queued for clients",
+            )
+            .unwrap(),
+            tables_active: IntGauge::new("blackjack_tables_active", "Open tables").unwrap(),
+            players_seated: IntGauge::new(
+                "blackjack_players_seated",
+                "Players seated across all tables",
+            )
+            .unwrap(),
+            rounds_completed: IntCounter::new(
+                "blackjack_rounds_completed_total",
+                "Rounds played to the end",
+            )
+            .unwrap(),
+            hands: IntCounterVec::new(
+                Opts::new("blackjack_hands_total", "Hands settled, by outcome"),
+                &["outcome"],
+            )
+            .unwrap(),
+            chips_wagered: IntCounter::new(
+                "blackjack_chips_wagered_total",
+                "Chips bet on settled hands",
+            )
+            .unwrap(),
+            chips_paid: IntCounter::new(
+                "blackjack_chips_paid_total",
+                "Chips paid out on settled hands, stakes included",
+            )
+            .unwrap(),
+            db_pool_connections: IntGauge::new(
+                "blackjack_db_pool_connections",
+                "Connections held by the database pool",
+            )
+            .unwrap(),
+            db_pool_idle: IntGauge::new(
+                "blackjack_db_pool_idle_connections",
+                "Idle connections in the database pool",
+            )
+            .unwrap(),
+            queue_depth: IntGaugeVec::new(
+                Opts::new(
+                    "blackjack_channel_queue_depth",
+                    "Commands waiting in each task's channel",
+                ),
+                &["channel"],
+            )
+            .unwrap(),
+            registry,
+        };
+
+        let collectors: [Box<dyn prometheus::core::Collector>; 12] = [
+            Box::new(metrics.ws_connections.clone()),
+            Box::new(metrics.ws_messages_received.clone()),
+            Box::new(metrics.ws_messages_sent.clone()),
+            Box::new(metrics.tables_active.clone()),
+            Box::new(metrics.players_seated.clone()),
+            Box::new(metrics.rounds_completed.clone()),
+            Box::new(metrics.hands.clone()),
+            Box::new(metrics.chips_wagered.clone()),
+            Box::new(metrics.chips_paid.clone()),
+            Box::new(metrics.db_pool_connections.clone()),
+            Box::new(metrics.db_pool_idle.clone()),
+            Box::new(metrics.queue_depth.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector).unwrap();
+        }
+        metrics
+    }
+
+    pub fn hand_settled(&self, outcome: Outcome, bet: u32, payout: u32) {
+        let label = match outcome {
+            Outcome::Blackjack => "blackjack",
+            Outcome::Win => "win",
+            Outcome::Lose => "lose",
+            Outcome::Push => "push",
+        };
+        self.hands.with_label_values(&[label]).inc();
+        self.chips_wagered.inc_by(bet as u64);
+        self.chips_paid.inc_by(payout as u64);
+    }
+}
+
+fn queue_depth<T>(send: &mpsc::Sender<T>) -> i64 {
+    (send.max_capacity() - send.capacity()) as i64
+}
+
+/// Prometheus text exposition of every metric.
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "health",
+    responses((status = 200, description = "Prometheus text format", content_type = "text/plain"))
+)]
+pub async fn metrics_handler(State(app_state): State<Arc<AppState>>) -> impl IntoResponse {
+    let metrics = get();
+
+    if let Some(pool) = app_state.users.pool_state() {
+        metrics.db_pool_connections.set(pool.connections as i64);
+        metrics.db_pool_idle.set(pool.idle_connections as i64);
+    }
+    for (channel, depth) in [
+        ("websocket_manager", queue_depth(&app_state.wm_send)),
+        ("game", queue_depth(&app_state.game_send)),
+        ("ledger", queue_depth(&app_state.ledger_send)),
+    ] {
+        metrics.queue_depth.with_label_values(&[channel]).set(depth);
+    }
+
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    if let Err(e) = encoder.encode(&metrics.registry.gather(), &mut body) {
+        tracing::error!(error = %e, "could not encode metrics");
+    }
+    (
+        [(header::CONTENT_TYPE, encoder.format_type().to_string())],
+        body,
+    )
+}
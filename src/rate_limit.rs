@@ -0,0 +1,227 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::{config::RateLimitConfig, error::ApiError};
+
+// How often clients whose buckets have refilled are forgotten.
+pub const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Rejected messages a connection regains per second. A client that only
+// now and then goes over the limit never runs out.
+const STRIKE_REFILL_PER_SEC: f64 = 1.0;
+
+/// Classic token bucket: holds up to `capacity` tokens and regains
+/// `refill_per_sec` of them every second. Each allowed action takes one.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    refill_per_sec: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: u32, refill_per_sec: f64) -> Self {
+        TokenBucket {
+            capacity: capacity as f64,
+            tokens: capacity as f64,
+            refill_per_sec,
+            last: Instant::now(),
+        }
+    }
+
+    pub fn try_take(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Whether the bucket has refilled completely, making it no different
+    /// from a new one.
+    pub fn is_full(&self) -> bool {
+        let elapsed = self.last.elapsed().as_secs_f64();
+        self.tokens + elapsed * self.refill_per_sec >= self.capacity
+    }
+}
+
+/// A token bucket per client IP address, created on first use.
+pub struct IpRateLimiter {
+    capacity: u32,
+    refill_per_sec: f64,
+    buckets: Mutex<HashMap<IpAddr, TokenBucket>>,
+}
+
+impl IpRateLimiter {
+    pub fn new(capacity: u32, refill_per_sec: f64) -> Self {
+        IpRateLimiter {
+            capacity,
+            refill_per_sec,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn check(&self, ip: IpAddr) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets
+            .entry(ip)
+            .or_insert_with(|| TokenBucket::new(self.capacity, self.refill_per_sec))
+            .try_take()
+    }
+
+    /// Forgets clients whose bucket is full again, so the map only holds
+    /// recently active addresses.
+    pub fn prune(&self) {
+        self.buckets.lock().unwrap().retain(|_, x| !x.is_full());
+    }
+}
+
+/// Middleware answering 429 once the client's IP has used up its bucket.
+pub async fn limit_by_ip(
+    State(limiter): State<Arc<IpRateLimiter>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    req: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    if !limiter.check(addr.ip()) {
+        return Err(ApiError::TooManyRequests(
+            "too many requests, slow down".to_string(),
+        ));
+    }
+    Ok(next.run(req).await)
+}
+
+/// What to do with an inbound WebSocket message.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    Reject,
+    Disconnect,
+}
+
+/// Limits the messages one connection may send. Messages over the rate are
+/// rejected, and a connection that keeps sending regardless is cut off.
+pub struct MessageLimiter {
+    messages: TokenBucket,
+    strikes: TokenBucket,
+}
+
+impl MessageLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        MessageLimiter {
+            messages: TokenBucket::new(config.ws_burst, config.ws_per_sec),
+            strikes: TokenBucket::new(config.ws_max_rejected, STRIKE_REFILL_PER_SEC),
+        }
+    }
+
+    pub fn check(&mut self) -> Verdict {
+        if self.messages.try_take() {
+            Verdict::Allow
+        } else if self.strikes.try_take() {
+            Verdict::Reject
+        } else {
+            Verdict::Disconnect
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+
+    // Slow enough that nothing refills while a test runs
+    const NO_REFILL: f64 = 1e-9;
+
+    fn limiter(burst: u32, per_sec: f64, max_rejected: u32) -> MessageLimiter {
+        MessageLimiter::new(&RateLimitConfig {
+            ws_burst: burst,
+            ws_per_sec: per_sec,
+            ws_max_rejected: max_rejected,
+            ..RateLimitConfig::default()
+        })
+    }
+
+    #[test]
+    fn buckets_allow_a_burst_then_refill() {
+        let mut bucket = TokenBucket::new(2, NO_REFILL);
+        assert!(bucket.is_full());
+        assert!(bucket.try_take());
+        assert!(!bucket.is_full());
+        assert!(bucket.try_take());
+        assert!(!bucket.try_take());
+
+        let mut bucket = TokenBucket::new(2, 100.0);
+        assert!(bucket.try_take() && bucket.try_take());
+        sleep(Duration::from_millis(50));
+        assert!(bucket.is_full());
+        // Refilling stops at the capacity
+        assert!(bucket.try_take() && bucket.try_take());
+        assert!(!bucket.try_take());
+    }
+
+    #[test]
+    fn addresses_have_their_own_buckets() {
+        let limiter = IpRateLimiter::new(1, NO_REFILL);
+        let (a, b) = (IpAddr::from([10, 0, 0, 1]), IpAddr::from([10, 0, 0, 2]));
+        assert!(limiter.check(a));
+        assert!(!limiter.check(a));
+        assert!(limiter.check(b));
+
+        // Pruning keeps buckets that are still in use
+        limiter.prune();
+        assert!(!limiter.check(a));
+    }
+
+    #[test]
+    fn pruned_addresses_start_over() {
+        let limiter = IpRateLimiter::new(1, 100.0);
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        assert!(limiter.check(ip));
+        sleep(Duration::from_millis(50));
+        limiter.prune();
+        assert!(limiter.buckets.lock().unwrap().is_empty());
+        assert!(limiter.check(ip));
+    }
+
+    #[test]
+    fn messages_over_the_rate_are_rejected_then_disconnected() {
+        let mut limiter = limiter(2, NO_REFILL, 3);
+        assert_eq!(limiter.check(), Verdict::Allow);
+        assert_eq!(limiter.check(), Verdict::Allow);
+        assert_eq!(limiter.check(), Verdict::Reject);
+        assert_eq!(limiter.check(), Verdict::Reject);
+        assert_eq!(limiter.check(), Verdict::Reject);
+        assert_eq!(limiter.check(), Verdict::Disconnect);
+    }
+
+    #[test]
+    fn a_connection_that_slows_down_is_allowed_again() {
+        let mut limiter = limiter(1, 100.0, 2);
+        assert_eq!(limiter.check(), Verdict::Allow);
+        assert_eq!(limiter.check(), Verdict::Reject);
+        sleep(Duration::from_millis(50));
+        assert_eq!(limiter.check(), Verdict::Allow);
+        // Only one rejection is left to use
+        assert_eq!(limiter.check(), Verdict::Reject);
+        assert_eq!(limiter.check(), Verdict::Disconnect);
+    }
+}
@@ -0,0 +1,44 @@
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    path::Path,
+};
+
+use rustls::{ClientConfig, RootCertStore};
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+/// Makes ring the crypto provider rustls uses when none is given, which
+/// has to happen before any TLS configuration is built.
+pub fn install_crypto_provider() {
+    // Fails only if a provider is already installed, which is just as good
+    let _ = rustls::crypto::ring::default_provider().install_default();
+}
+
+/// Connector for Postgres over TLS. The server certificate is checked
+/// against the CA bundle at `ca_file`, or against the Mozilla root
+/// certificates without one.
+pub fn postgres_connector(ca_file: Option<&Path>) -> io::Result<MakeRustlsConnect> {
+    let mut roots = RootCertStore::empty();
+    match ca_file {
+        Some(path) => {
+            let mut reader = BufReader::new(File::open(path)?);
+            for cert in rustls_pemfile::certs(&mut reader) {
+                roots
+                    .add(cert?)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            }
+            if roots.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("no certificates found in {}", path.display()),
+                ));
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(MakeRustlsConnect::new(config))
+}
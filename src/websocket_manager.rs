@@ -1,107 +1,769 @@
-use std::{collections::HashMap, net::SocketAddr, ops::ControlFlow};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    time::Duration,
+};
 
-use axum::extract::ws::{Message, WebSocket};
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket};
 use futures_util::{stream::SplitSink, SinkExt};
-use http::StatusCode;
 use serde::{Deserialize, Serialize};
-use tokio::sync::oneshot;
+use tokio::sync::{
+    mpsc::{self, error::TrySendError},
+    oneshot,
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{debug, warn, Instrument};
+use utoipa::ToSchema;
 use uuid::Uuid;
 
-#[derive(Debug, Deserialize, Serialize)]
+use crate::{
+    blackjack::game::TableView,
+    chat::{ChatError, ChatMessage, ChatScope},
+    metrics,
+};
+
+// Number of outbound messages that can be queued for a single connection
+// before it is considered too slow and disconnected.
+pub const OUTBOUND_QUEUE_SIZE: usize = 32;
+
+// How long a writer task waits on a single socket write before giving up,
+// unless configured otherwise.
+pub const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Identifies one open socket. A user may have several open at once, so
+/// each gets its own id; the name they go by is kept by the manager rather
+/// than in the key, so it can change while they are connected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ConnKey {
+    pub id: Uuid,
+    pub user_id: Uuid,
+}
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
 pub enum MsgType {
     UserAdded,
     Data,
     SelfUuid,
     UpdateUserList,
+    Chat,
+    Error,
+    TableJoined,
+    TableLeft,
+    Muted,
+    Unmuted,
+    TableState,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Messages sent to clients. `msg_type` says which of the other fields is
+/// filled in.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
 pub struct SendWS {
     pub msg_type: MsgType,
     pub msg_data_str: Option<String>,
+    /// Connected users as `[name, id]` pairs.
+    #[schema(value_type = Option<Vec<Vec<String>>>)]
     pub msg_data_keys: Option<Vec<(String, Uuid)>>,
     pub msg_data_arr: Option<String>,
+    pub msg_data_chat: Option<ChatMessage>,
+    pub msg_data_table: Option<TableView>,
+}
+
+impl SendWS {
+    pub fn new(msg_type: MsgType) -> Self {
+        SendWS {
+            msg_type,
+            msg_data_str: None,
+            msg_data_keys: None,
+            msg_data_arr: None,
+            msg_data_chat: None,
+            msg_data_table: None,
+        }
+    }
+
+    pub fn error(message: impl ToString) -> Self {
+        SendWS {
+            msg_data_str: Some(message.to_string()),
+            ..SendWS::new(MsgType::Error)
+        }
+    }
+}
+
+/// Messages sent by clients, tagged by `msg_type`, e.g.
+/// `{"msg_type": "JoinTable", "table_id": "..."}`.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+#[serde(tag = "msg_type")]
+pub enum RecvWS {
+    CreateTable { seat: Option<u8> },
+    JoinTable { table_id: Uuid, seat: Option<u8> },
+    WatchTable { table_id: Uuid },
+    LeaveTable,
+    Bet { spots: Vec<u32> },
+    Deal,
+    Hit,
+    Stand,
+    Split,
+    Chat { scope: ChatScope, text: String },
+    Mute { user_id: Uuid },
+    Unmute { user_id: Uuid },
 }
 
 #[derive(Debug)]
 pub enum Command {
+    /// Registers a connection. Cancelling `cancel` ends the connection's
+    /// reader, which the manager does when it drops the connection.
     AddWS {
-        ws_send: SplitSink<WebSocket, Message>,
+        ws_send: mpsc::Sender<Message>,
+        cancel: CancellationToken,
         resp: oneshot::Sender<Uuid>,
-        key: (String, Uuid),
+        key: ConnKey,
+        name: String,
     },
     DeleteWS {
-        key: (String, Uuid),
+        key: ConnKey,
     },
     SendWS {
-        key: (String, Uuid),
+        key: ConnKey,
         msg: SendWS,
     },
     UpdateUserList {},
+    CreateRoom {
+        table_id: Uuid,
+        host: Uuid,
+    },
+    RemoveRoom {
+        table_id: Uuid,
+    },
+    SetRoomHost {
+        table_id: Uuid,
+        host: Uuid,
+    },
+    JoinRoom {
+        table_id: Uuid,
+        key: ConnKey,
+    },
+    LeaveRoom {
+        table_id: Uuid,
+        key: ConnKey,
+    },
+    BroadcastTable {
+        table_id: Uuid,
+        msg: SendWS,
+    },
+    /// A chat message from `key`, sent under the name the user goes by now.
+    Chat {
+        key: ConnKey,
+        scope: ChatScope,
+        table_id: Option<Uuid>,
+        text: String,
+    },
+    Mute {
+        table_id: Uuid,
+        key: ConnKey,
+        target: Uuid,
+        muted: bool,
+    },
+    Disconnect {
+        user_id: Uuid,
+        reason: String,
+    },
+    /// Shows a user under their new name on every connection they have
+    /// open, and in everyone's user list.
+    Rename {
+        user_id: Uuid,
+        name: String,
+    },
+    /// Closes a single connection that broke the rules.
+    Kick {
+        key: ConnKey,
+        reason: String,
+    },
+    /// Answers with every user that has a connection open.
+    ConnectedUsers {
+        resp: oneshot::Sender<Vec<Uuid>>,
+    },
+    /// Answers straight away, showing the manager task is alive.
+    Ping {
+        resp: oneshot::Sender<()>,
+    },
+    /// Closes every connection, answering once the close frames are queued.
+    CloseAll {
+        reason: String,
+        resp: oneshot::Sender<()>,
+    },
+}
+
+#[derive(Debug)]
+pub enum WsError {
+    NotConnected(ConnKey),
+    QueueFull(ConnKey),
+    Closed(ConnKey),
+    Serialize(serde_json::Error),
+}
+
+impl fmt::Display for WsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WsError::NotConnected(key) => write!(f, "{:?} is not connected", key),
+            WsError::QueueFull(key) => {
+                write!(f, "outbound queue full for {:?}, disconnected", key)
+            }
+            WsError::Closed(key) => write!(f, "connection for {:?} is closed", key),
+            WsError::Serialize(e) => write!(f, "could not serialize message: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for WsError {}
+
+impl From<serde_json::Error> for WsError {
+    fn from(err: serde_json::Error) -> Self {
+        WsError::Serialize(err)
+    }
+}
+
+/// Spawns the task that owns the write half of a socket and drains its
+/// outbound queue. The task ends, closing the socket, once every sender for
+/// the queue has been dropped or a write fails. The task is tracked by
+/// `writers`, so shutdown can wait for close frames to go out.
+pub fn spawn_writer(
+    mut sink: SplitSink<WebSocket, Message>,
+    write_timeout: Duration,
+    writers: &TaskTracker,
+) -> mpsc::Sender<Message> {
+    let (ws_send, mut ws_recv) = mpsc::channel::<Message>(OUTBOUND_QUEUE_SIZE);
+
+    writers.spawn(
+        async move {
+            while let Some(msg) = ws_recv.recv().await {
+                match tokio::time::timeout(write_timeout, sink.send(msg)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => {
+                        debug!(error = %e, "socket write failed");
+                        break;
+                    }
+                    Err(_) => {
+                        warn!(?write_timeout, "socket write timed out");
+                        break;
+                    }
+                }
+            }
+            let _ = sink.close().await;
+        }
+        .in_current_span(),
+    );
+
+    ws_send
+}
+
+/// Connections seated at (or watching) a table. Table chat is only
+/// delivered to members, and the host can mute members of their room.
+struct Room {
+    host: Uuid,
+    members: HashSet<ConnKey>,
+    muted: HashSet<Uuid>,
+}
+
+struct Connection {
+    name: String,
+    sender: mpsc::Sender<Message>,
+    cancel: CancellationToken,
 }
 
 pub struct WebSocketManager {
-    ws_map: HashMap<(String, Uuid), SplitSink<WebSocket, Message>>,
+    ws_map: HashMap<ConnKey, Connection>,
+    rooms: HashMap<Uuid, Room>,
 }
 
 impl WebSocketManager {
     pub fn new() -> Self {
         WebSocketManager {
             ws_map: HashMap::new(),
+            rooms: HashMap::new(),
         }
     }
 
     pub fn add_ws(
         &mut self,
-        key: (String, Uuid),
-        sender: SplitSink<WebSocket, Message>,
-    ) -> (StatusCode, String) {
-        match self.ws_map.insert(key.clone(), sender) {
-            None => {
-                println!("add new {:?}", self.ws_map[&key]);
-                (StatusCode::OK, "ws was added".to_string())
+        key: ConnKey,
+        name: String,
+        sender: mpsc::Sender<Message>,
+        cancel: CancellationToken,
+    ) {
+        debug!(conn_id = %key.id, user_id = %key.user_id, user = %name, "connection added");
+        let conn = Connection {
+            name,
+            sender,
+            cancel,
+        };
+        self.ws_map.insert(key, conn);
+        metrics::get().ws_connections.set(self.ws_map.len() as i64);
+    }
+
+    /// Sends the current user list to every connection.
+    pub fn update_all_list(&mut self) -> Result<(), WsError> {
+        let msg = SendWS {
+            msg_data_keys: Some(self.user_list()),
+            ..SendWS::new(MsgType::UpdateUserList)
+        };
+        let keys = self.connections();
+        self.broadcast(keys, &msg)
+    }
+
+    /// Connected users as `(name, id)`, once each however many connections
+    /// they have open.
+    pub fn user_list(&self) -> Vec<(String, Uuid)> {
+        let users: HashMap<Uuid, &String> = self
+            .ws_map
+            .iter()
+            .map(|(key, conn)| (key.user_id, &conn.name))
+            .collect();
+        users
+            .into_iter()
+            .map(|(id, name)| (name.clone(), id))
+            .collect()
+    }
+
+    pub fn connected_users(&self) -> Vec<Uuid> {
+        let users: HashSet<Uuid> = self.ws_map.keys().map(|key| key.user_id).collect();
+        users.into_iter().collect()
+    }
+
+    fn connections(&self) -> Vec<ConnKey> {
+        self.ws_map.keys().copied().collect()
+    }
+
+    pub fn send_msg(&mut self, key: ConnKey, msg: SendWS) -> Result<(), WsError> {
+        let text = serde_json::ser::to_string(&msg)?;
+        self.enqueue(key, Message::Text(text))
+    }
+
+    /// Forgets a connection and ends its reader, which then takes the user
+    /// away from their table. Dropping the sender ends the writer.
+    pub fn remove_ws(&mut self, key: ConnKey) {
+        for room in self.rooms.values_mut() {
+            room.members.remove(&key);
+        }
+        if let Some(conn) = self.ws_map.remove(&key) {
+            conn.cancel.cancel();
+        }
+        metrics::get().ws_connections.set(self.ws_map.len() as i64);
+    }
+
+    pub fn create_room(&mut self, table_id: Uuid, host: Uuid) {
+        self.rooms.insert(
+            table_id,
+            Room {
+                host,
+                members: HashSet::new(),
+                muted: HashSet::new(),
+            },
+        );
+    }
+
+    /// Drops a closed table's room, telling anyone still in it.
+    pub fn remove_room(&mut self, table_id: Uuid) -> Result<(), WsError> {
+        let Some(room) = self.rooms.remove(&table_id) else {
+            return Ok(());
+        };
+        let msg = SendWS {
+            msg_data_str: Some(table_id.to_string()),
+            ..SendWS::new(MsgType::TableLeft)
+        };
+        self.broadcast(room.members.into_iter().collect(), &msg)
+    }
+
+    pub fn set_room_host(&mut self, table_id: Uuid, host: Uuid) {
+        if let Some(room) = self.rooms.get_mut(&table_id) {
+            room.host = host;
+            room.muted.remove(&host);
+        }
+    }
+
+    pub fn join_room(&mut self, table_id: Uuid, key: ConnKey) -> Result<(), WsError> {
+        let Some(room) = self.rooms.get_mut(&table_id) else {
+            return Ok(());
+        };
+        room.members.insert(key);
+        let msg = SendWS {
+            msg_data_str: Some(table_id.to_string()),
+            ..SendWS::new(MsgType::TableJoined)
+        };
+        self.send_msg(key, msg)
+    }
+
+    pub fn leave_room(&mut self, table_id: Uuid, key: ConnKey) -> Result<(), WsError> {
+        let Some(room) = self.rooms.get_mut(&table_id) else {
+            return Ok(());
+        };
+        if !room.members.remove(&key) {
+            return Ok(());
+        }
+        let msg = SendWS {
+            msg_data_str: Some(table_id.to_string()),
+            ..SendWS::new(MsgType::TableLeft)
+        };
+        self.send_msg(key, msg)
+    }
+
+    pub fn broadcast_table(&mut self, table_id: Uuid, msg: SendWS) -> Result<(), WsError> {
+        let Some(room) = self.rooms.get(&table_id) else {
+            return Ok(());
+        };
+        let members = room.members.iter().copied().collect();
+        self.broadcast(members, &msg)
+    }
+
+    /// Delivers a chat message to its scope. Moderation failures (muted,
+    /// not at the table) are reported back to the sender only.
+    pub fn chat(
+        &mut self,
+        key: ConnKey,
+        scope: ChatScope,
+        table_id: Option<Uuid>,
+        text: String,
+    ) -> Result<(), WsError> {
+        let Some(conn) = self.ws_map.get(&key) else {
+            return Err(WsError::NotConnected(key));
+        };
+        let msg = ChatMessage {
+            scope,
+            table_id,
+            from: (conn.name.clone(), key.user_id),
+            text,
+        };
+        let recipients = match self.chat_recipients(key, &msg) {
+            Ok(recipients) => recipients,
+            Err(e) => return self.send_msg(key, SendWS::error(e)),
+        };
+        let out = SendWS {
+            msg_data_chat: Some(msg),
+            ..SendWS::new(MsgType::Chat)
+        };
+        self.broadcast(recipients, &out)
+    }
+
+    pub fn mute(
+        &mut self,
+        table_id: Uuid,
+        key: ConnKey,
+        target: Uuid,
+        muted: bool,
+    ) -> Result<(), WsError> {
+        let room = match self.rooms.get_mut(&table_id) {
+            Some(room) if room.host == key.user_id => room,
+            Some(_) => return self.send_msg(key, SendWS::error(ChatError::NotHost)),
+            None => return self.send_msg(key, SendWS::error(ChatError::NotAtTable)),
+        };
+        if muted {
+            room.muted.insert(target);
+        } else {
+            room.muted.remove(&target);
+        }
+
+        let msg_type = if muted {
+            MsgType::Muted
+        } else {
+            MsgType::Unmuted
+        };
+        let msg = SendWS {
+            msg_data_str: Some(target.to_string()),
+            ..SendWS::new(msg_type)
+        };
+        let members = room.members.iter().copied().collect();
+        self.broadcast(members, &msg)
+    }
+
+    fn chat_recipients(&self, key: ConnKey, msg: &ChatMessage) -> Result<Vec<ConnKey>, ChatError> {
+        match msg.scope {
+            ChatScope::Lobby => Ok(self.connections()),
+            ChatScope::Table => {
+                let room = msg
+                    .table_id
+                    .and_then(|id| self.rooms.get(&id))
+                    .filter(|room| room.members.contains(&key))
+                    .ok_or(ChatError::NotAtTable)?;
+                if room.muted.contains(&key.user_id) {
+                    return Err(ChatError::Muted);
+                }
+                Ok(room.members.iter().copied().collect())
             }
-            Some(old) => {
-                println!("replaced old: {:?} with new: {:?}", old, self.ws_map[&key]);
-                (
-                    StatusCode::CONFLICT,
-                    "ws was not added due to conflicting keys".to_string(),
-                )
+        }
+    }
+
+    /// Serializes a message once and queues it for every key given.
+    /// Connections that cannot take it are dropped by `enqueue`, so only a
+    /// serialization failure is returned.
+    fn broadcast(&mut self, keys: Vec<ConnKey>, msg: &SendWS) -> Result<(), WsError> {
+        let text = serde_json::ser::to_string(msg)?;
+        for key in keys {
+            if let Err(e) = self.enqueue(key, Message::Text(text.clone())) {
+                warn!(error = %e, "dropped broadcast");
             }
         }
+        Ok(())
     }
 
-    pub async fn update_all_list(&mut self) {
-        let uuid_vec = self.get_all_uuids();
-        for (_, val) in &mut self.ws_map {
-            let msg = SendWS {
-                msg_type: MsgType::UpdateUserList,
-                msg_data_str: None,
-                msg_data_keys: Some(Vec::from_iter(uuid_vec.iter().map(|t| t.clone()))),
-                msg_data_arr: None,
-            };
-            let _ = val
-                .send(Message::Text(serde_json::ser::to_string(&msg).unwrap()))
-                .await;
+    pub fn rename_user(&mut self, user_id: Uuid, name: &str) -> Result<(), WsError> {
+        for (key, conn) in self.ws_map.iter_mut() {
+            if key.user_id == user_id {
+                conn.name = name.to_string();
+            }
         }
+        self.update_all_list()
     }
 
-    pub fn get_ws(&self, id: Uuid, name: String) -> Option<&SplitSink<WebSocket, Message>> {
-        self.ws_map.get(&(name, id))
+    /// Closes every connection a user has open, telling the client why.
+    pub fn disconnect_user(&mut self, user_id: Uuid, reason: &str) -> Result<(), WsError> {
+        let keys: Vec<ConnKey> = self
+            .ws_map
+            .keys()
+            .filter(|key| key.user_id == user_id)
+            .copied()
+            .collect();
+        for key in keys {
+            self.close(key, close_code::NORMAL, reason);
+        }
+        self.update_all_list()
+    }
+
+    pub fn kick(&mut self, key: ConnKey, reason: &str) -> Result<(), WsError> {
+        self.close(key, close_code::POLICY, reason);
+        self.update_all_list()
     }
 
-    pub fn get_all_uuids(&self) -> Vec<(String, Uuid)> {
-        Vec::from_iter(self.ws_map.keys().map(|x| x.clone()))
+    /// Closes every connection, for when the server is going away.
+    pub fn close_all(&mut self, reason: &str) {
+        for key in self.connections() {
+            self.close(key, close_code::AWAY, reason);
+        }
+        self.rooms.clear();
+    }
+
+    fn close(&mut self, key: ConnKey, code: u16, reason: &str) {
+        let frame = CloseFrame {
+            code,
+            reason: reason.to_string().into(),
+        };
+        // The writer sends the close frame and then ends, since its sender
+        // is dropped along with the connection
+        let _ = self.enqueue(key, Message::Close(Some(frame)));
+        self.remove_ws(key);
+    }
+
+    /// Queues a message for a connection without waiting on the socket.
+    ///
+    /// A client whose queue is full is not keeping up with the table and is
+    /// disconnected: its reader is stopped and dropping its sender ends the
+    /// writer task, which closes the socket, rather than letting it hold
+    /// back everybody else.
+    fn enqueue(&mut self, key: ConnKey, msg: Message) -> Result<(), WsError> {
+        let Some(conn) = self.ws_map.get(&key) else {
+            return Err(WsError::NotConnected(key));
+        };
+        match conn.sender.try_send(msg) {
+            Ok(()) => {
+                metrics::get().ws_messages_sent.inc();
+                Ok(())
+            }
+            Err(TrySendError::Full(_)) => {
+                self.remove_ws(key);
+                Err(WsError::QueueFull(key))
+            }
+            Err(TrySendError::Closed(_)) => {
+                self.remove_ws(key);
+                Err(WsError::Closed(key))
+            }
+        }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Client {
+        key: ConnKey,
+        queue: mpsc::Receiver<Message>,
+        cancel: CancellationToken,
+    }
+
+    impl Client {
+        /// Messages queued for the client so far.
+        fn received(&mut self) -> Vec<SendWS> {
+            let mut out = Vec::new();
+            while let Ok(msg) = self.queue.try_recv() {
+                if let Message::Text(text) = msg {
+                    out.push(serde_json::from_str(&text).unwrap());
+                }
+            }
+            out
+        }
+
+        fn chats(&mut self) -> Vec<String> {
+            self.received()
+                .into_iter()
+                .filter_map(|msg| msg.msg_data_chat)
+                .map(|chat| chat.text)
+                .collect()
+        }
+
+        fn errors(&mut self) -> Vec<String> {
+            self.received()
+                .into_iter()
+                .filter(|msg| matches!(msg.msg_type, MsgType::Error))
+                .filter_map(|msg| msg.msg_data_str)
+                .collect()
+        }
+    }
+
+    fn connect(wm: &mut WebSocketManager, name: &str, queue_size: usize) -> Client {
+        let key = ConnKey {
+            id: Uuid::new_v4(),
+            user_id: Uuid::new_v4(),
+        };
+        let (sender, queue) = mpsc::channel(queue_size);
+        let cancel = CancellationToken::new();
+        wm.add_ws(key, name.to_string(), sender, cancel.clone());
+        Client { key, queue, cancel }
+    }
+
+    /// A manager with a table hosted by the first client, which the second
+    /// has joined and the third has not.
+    fn table() -> (WebSocketManager, Uuid, [Client; 3]) {
+        let mut wm = WebSocketManager::new();
+        let mut clients = [
+            connect(&mut wm, "host", OUTBOUND_QUEUE_SIZE),
+            connect(&mut wm, "player", OUTBOUND_QUEUE_SIZE),
+            connect(&mut wm, "outsider", OUTBOUND_QUEUE_SIZE),
+        ];
+        let table_id = Uuid::new_v4();
+        wm.create_room(table_id, clients[0].key.user_id);
+        wm.join_room(table_id, clients[0].key).unwrap();
+        wm.join_room(table_id, clients[1].key).unwrap();
+        for client in clients.iter_mut() {
+            client.received();
+        }
+        (wm, table_id, clients)
+    }
+
+    fn say(wm: &mut WebSocketManager, client: &Client, table_id: Option<Uuid>, text: &str) {
+        let scope = match table_id {
+            Some(_) => ChatScope::Table,
+            None => ChatScope::Lobby,
+        };
+        wm.chat(client.key, scope, table_id, text.to_string())
+            .unwrap();
+    }
+
+    #[test]
+    fn lobby_chat_reaches_everyone() {
+        let (mut wm, _, [host, mut player, mut outsider]) = table();
+        say(&mut wm, &host, None, "hello all");
+        assert_eq!(player.chats(), ["hello all"]);
+        assert_eq!(outsider.chats(), ["hello all"]);
+    }
+
+    #[test]
+    fn table_chat_reaches_only_the_table() {
+        let (mut wm, table_id, [mut host, player, mut outsider]) = table();
+        say(&mut wm, &player, Some(table_id), "good luck");
+        assert_eq!(host.chats(), ["good luck"]);
+        assert!(outsider.chats().is_empty());
+
+        // Someone not at the table is told so, and nobody else hears it
+        say(&mut wm, &outsider, Some(table_id), "let me in");
+        assert_eq!(outsider.errors(), [ChatError::NotAtTable.to_string()]);
+        assert!(host.chats().is_empty());
+        say(&mut wm, &outsider, Some(Uuid::new_v4()), "anyone?");
+        assert_eq!(outsider.errors(), [ChatError::NotAtTable.to_string()]);
+    }
+
+    #[test]
+    fn only_the_host_mutes_and_muted_players_stay_quiet_at_the_table() {
+        let (mut wm, table_id, [mut host, mut player, mut outsider]) = table();
+        let target = player.key.user_id;
+
+        wm.mute(table_id, player.key, host.key.user_id, true)
+            .unwrap();
+        assert_eq!(player.errors(), [ChatError::NotHost.to_string()]);
+        wm.mute(Uuid::new_v4(), host.key, target, true).unwrap();
+        assert_eq!(host.errors(), [ChatError::NotAtTable.to_string()]);
+
+        wm.mute(table_id, host.key, target, true).unwrap();
+        assert!(player
+            .received()
+            .iter()
+            .any(|msg| matches!(msg.msg_type, MsgType::Muted)));
+        say(&mut wm, &player, Some(table_id), "but");
+        assert_eq!(player.errors(), [ChatError::Muted.to_string()]);
+        assert!(host.chats().is_empty());
+
+        // The lobby is not the host's to moderate
+        say(&mut wm, &player, None, "still here");
+        assert_eq!(host.chats(), ["still here"]);
+        assert_eq!(outsider.chats(), ["still here"]);
+
+        wm.mute(table_id, host.key, target, false).unwrap();
+        player.received();
+        say(&mut wm, &player, Some(table_id), "thanks");
+        assert_eq!(host.chats(), ["thanks"]);
+    }
+
+    #[test]
+    fn chat_goes_out_under_the_current_name() {
+        let (mut wm, _, [host, mut player, _]) = table();
+        wm.rename_user(host.key.user_id, "renamed").unwrap();
+        player.received();
+        say(&mut wm, &host, None, "hi");
+        let chat = player.received().pop().unwrap().msg_data_chat.unwrap();
+        assert_eq!(chat.from, ("renamed".to_string(), host.key.user_id));
+    }
+
+    #[test]
+    fn a_full_queue_drops_the_connection() {
+        let mut wm = WebSocketManager::new();
+        let mut slow = connect(&mut wm, "slow", 2);
+        let mut other = connect(&mut wm, "other", OUTBOUND_QUEUE_SIZE);
+        let msg = || SendWS::new(MsgType::Data);
+
+        wm.send_msg(slow.key, msg()).unwrap();
+        wm.send_msg(slow.key, msg()).unwrap();
+        assert!(!slow.cancel.is_cancelled());
+        assert!(matches!(
+            wm.send_msg(slow.key, msg()),
+            Err(WsError::QueueFull(key)) if key == slow.key
+        ));
+        // The reader is stopped and the user is gone from the list
+        assert!(slow.cancel.is_cancelled());
+        assert!(matches!(
+            wm.send_msg(slow.key, msg()),
+            Err(WsError::NotConnected(_))
+        ));
+        assert_eq!(wm.connected_users(), [other.key.user_id]);
+        // What was queued before still goes out, then the queue ends
+        assert_eq!(slow.received().len(), 2);
+        assert!(slow.queue.is_closed());
 
-    pub async fn send_msg(&mut self, key: (String, Uuid), msg: SendWS) {
-        let ws_send = self.ws_map.get_mut(&key).unwrap();
-        let _ = ws_send
-            .send(Message::Text(serde_json::ser::to_string(&msg).unwrap()))
-            .await;
+        // Broadcasts drop a slow connection without holding up the rest
+        let slower = connect(&mut wm, "slower", 1);
+        wm.update_all_list().unwrap();
+        wm.update_all_list().unwrap();
+        assert!(slower.cancel.is_cancelled());
+        assert_eq!(other.received().len(), 2);
     }
 
-    pub fn remove_ws(&mut self, key: (String, Uuid)) -> Option<SplitSink<WebSocket, Message>> {
-        self.ws_map.remove(&key)
+    #[test]
+    fn a_closed_queue_drops_the_connection() {
+        let mut wm = WebSocketManager::new();
+        let gone = connect(&mut wm, "gone", OUTBOUND_QUEUE_SIZE);
+        let Client { key, queue, cancel } = gone;
+        // The writer ending drops the receiving half
+        drop(queue);
+        assert!(matches!(
+            wm.send_msg(key, SendWS::new(MsgType::Data)),
+            Err(WsError::Closed(closed)) if closed == key
+        ));
+        assert!(cancel.is_cancelled());
+        assert!(wm.connected_users().is_empty());
     }
 }